@@ -0,0 +1,178 @@
+use std::{net::{Ipv4Addr, UdpSocket}, thread, time::{Duration, Instant}};
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Nonce};
+use chacha20poly1305::ChaCha20Poly1305;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rand::rngs::OsRng;
+use tokio::runtime::Runtime;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use frida_vpn::config::{CipherSuite, RekeyConfig};
+use frida_vpn::noise::{Initiator, Keypair, Responder, TransportKeys};
+use frida_vpn::server::Pipeline;
+use frida_vpn::session::{Session, Sessions};
+use frida_vpn::udp::{self, Message, UDPSerializable, UDPVpnPacket};
+
+// Pushes tunnel-sized packets through a pair of loopback sockets: encrypt on
+// one side, send, receive, decrypt on the other. The per-packet baseline
+// sets up the cipher and allocates for every packet, as the data path used to.
+const PACKET_SIZE: usize = 1400;
+
+fn keypair() -> Keypair {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    Keypair { secret, public }
+}
+
+fn handshake() -> (TransportKeys, TransportKeys) {
+    let client = keypair();
+    let server = keypair();
+    let (initiator, initiation) = Initiator::new(&client, &server.public, &[]).unwrap();
+    let (responder, _) = Responder::consume_initiation(&server, &initiation).unwrap();
+    let (response, server_keys) = responder.respond(&[], None).unwrap();
+    let (client_keys, _) = initiator.consume_response(&response, None).unwrap();
+    (client_keys, server_keys)
+}
+
+fn loopback() -> (UdpSocket, UdpSocket) {
+    let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+    a.connect(b.local_addr().unwrap()).unwrap();
+    b.connect(a.local_addr().unwrap()).unwrap();
+    (a, b)
+}
+
+fn per_packet_seal(suite: CipherSuite, key: &[u8; 32], counter: u64, plain: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    let nonce = Nonce::from_slice(&nonce);
+    let mut data = match suite {
+        CipherSuite::Aes256Gcm => Aes256Gcm::new(key.into()).encrypt(nonce, plain),
+        CipherSuite::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into()).encrypt(nonce, plain)
+    }.unwrap();
+    UDPVpnPacket { receiver: 0, counter, data: &mut data }.serialize()
+}
+
+fn per_packet_open(suite: CipherSuite, key: &[u8; 32], packet: &UDPVpnPacket) -> Vec<u8> {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&packet.counter.to_le_bytes());
+    let nonce = Nonce::from_slice(&nonce);
+    match suite {
+        CipherSuite::Aes256Gcm => Aes256Gcm::new(key.into()).decrypt(nonce, &*packet.data),
+        CipherSuite::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into()).decrypt(nonce, &*packet.data)
+    }.unwrap()
+}
+
+fn bench_suite(c: &mut Criterion, name: &str, suite: CipherSuite) {
+    let rekey = RekeyConfig { max_bytes: u64::MAX, max_packets: u64::MAX, ..RekeyConfig::default() };
+    let plain = vec![0x45u8; PACKET_SIZE];
+    let mut recv_buf = [0u8; 4096];
+    let (tx, rx) = loopback();
+
+    let mut group = c.benchmark_group(format!("loopback/{}", name));
+    group.throughput(Throughput::Bytes(PACKET_SIZE as u64));
+
+    let (client_keys, server_keys) = handshake();
+    let mut client = Sessions::default();
+    let mut server = Sessions::default();
+    client.rotate(Session::new(client_keys, suite, 1, 2));
+    server.rotate(Session::new(server_keys, suite, 2, 1));
+    let mut wire = Vec::new();
+    group.bench_function("cached", |b| b.iter(|| {
+        client.encrypt(&plain, &mut wire, &rekey);
+        tx.send(&wire).unwrap();
+        let len = rx.recv(&mut recv_buf).unwrap();
+        let Ok(Message::Packet(mut packet)) = udp::decode(&mut recv_buf[..len]) else { unreachable!() };
+        server.decrypt(&mut packet, &rekey).unwrap().len()
+    }));
+
+    let (client_keys, server_keys) = handshake();
+    let mut counter = 0u64;
+    group.bench_function("per_packet", |b| b.iter(|| {
+        let wire = per_packet_seal(suite, &client_keys.send, counter, &plain);
+        counter += 1;
+        tx.send(&wire).unwrap();
+        let len = rx.recv(&mut recv_buf).unwrap();
+        let Ok(Message::Packet(packet)) = udp::decode(&mut recv_buf[..len]) else { unreachable!() };
+        per_packet_open(suite, &server_keys.recv, &packet)
+    }));
+
+    group.finish();
+}
+
+const PEERS: usize = 64;
+// Datagrams in flight at a time, few enough that none overflow the socket.
+const WINDOW: u64 = 64;
+
+/// A tunnelled packet from `source` to the server's tunnel address.
+fn ip_packet(source: Ipv4Addr) -> Vec<u8> {
+    let mut packet = vec![0u8; PACKET_SIZE];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(PACKET_SIZE as u16).to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 17;
+    packet[12..16].copy_from_slice(&source.octets());
+    packet[16..20].copy_from_slice(&[10, 66, 66, 1]);
+    packet
+}
+
+// Runs the server's data plane with 1, 2, 4... workers up to the core count.
+// Datagrams of many peers go from a loopback socket through the reader and
+// the workers until they come out decrypted for the tun device.
+fn bench_workers(c: &mut Criterion) {
+    let rekey = RekeyConfig { max_bytes: u64::MAX, max_packets: u64::MAX, ..RekeyConfig::default() };
+    let suite = CipherSuite::ChaCha20Poly1305;
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+
+    let mut group = c.benchmark_group("workers");
+    group.throughput(Throughput::Elements(1));
+    let mut workers = 1;
+    while workers <= cores {
+        let runtime = Runtime::new().unwrap();
+        let (client, server) = loopback();
+        server.set_nonblocking(true).unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let (mut pipeline, mut peers) = runtime.block_on(async {
+            let pipeline = Pipeline::start(tokio::net::UdpSocket::from_std(server).unwrap(), workers);
+            let mut peers = Vec::new();
+            for i in 0..PEERS as u32 {
+                let ip = Ipv4Addr::new(10, 66, 67, i as u8);
+                let (client_keys, server_keys) = handshake();
+                let index = pipeline.add_peer(ip.into(), client_addr, server_keys, suite, i).await;
+                let mut sessions = Sessions::default();
+                sessions.rotate(Session::new(client_keys, suite, i, index));
+                peers.push((sessions, ip_packet(ip)));
+            }
+            (pipeline, peers)
+        });
+        group.bench_function(workers.to_string(), |b| b.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            let mut sent = 0;
+            while sent < iters {
+                let window = (iters - sent).min(WINDOW);
+                let wire = (sent..sent + window).map(|i| {
+                    let (sessions, packet) = &mut peers[i as usize % PEERS];
+                    let mut datagram = Vec::new();
+                    sessions.encrypt(packet, &mut datagram, &rekey);
+                    datagram
+                }).collect::<Vec<_>>();
+                let start = Instant::now();
+                wire.iter().for_each(|datagram| { client.send(datagram).unwrap(); });
+                runtime.block_on(async { for _ in 0..window { pipeline.delivered().await.unwrap(); } });
+                elapsed += start.elapsed();
+                sent += window;
+            }
+            elapsed
+        }));
+        workers *= 2;
+    }
+    group.finish();
+}
+
+fn throughput(c: &mut Criterion) {
+    bench_suite(c, "aes256gcm", CipherSuite::Aes256Gcm);
+    bench_suite(c, "chacha20poly1305", CipherSuite::ChaCha20Poly1305);
+    bench_workers(c);
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -0,0 +1,321 @@
+use std::{io, net::SocketAddr, ops::Deref};
+#[cfg(target_os = "linux")]
+use std::{os::fd::AsRawFd, sync::atomic::{AtomicBool, Ordering}};
+use log::{debug, info, warn};
+use tokio::net::UdpSocket;
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+
+/// Datagrams read or written per syscall at most.
+pub const BATCH: usize = 32;
+
+/// Room for one receive coalesced by GRO.
+#[cfg(target_os = "linux")]
+const GRO_BUF_LEN: usize = 65535;
+
+/// A UDP socket that moves datagrams in batches. On Linux a batch takes one
+/// `recvmmsg` or `sendmmsg`, and datagrams of one flow are coalesced with
+/// UDP GRO and split again with UDP GSO where the kernel does that. Other
+/// platforms, and kernels lacking a feature, go one datagram at a time.
+pub struct BatchSocket {
+    sock: UdpSocket,
+    #[cfg(target_os = "linux")]
+    mmsg: AtomicBool,
+    #[cfg(target_os = "linux")]
+    gso: AtomicBool,
+    #[cfg(target_os = "linux")]
+    gro: bool
+}
+
+/// Buffers for one batched receive.
+pub struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    received: Vec<(usize, usize, SocketAddr)> // length, segment size, sender
+}
+
+impl RecvBatch {
+    /// Datagrams of the last receive, writable so they can be decrypted in
+    /// place. A GRO buffer yields each segment.
+    pub fn datagrams(&mut self) -> impl Iterator<Item = (&mut [u8], SocketAddr)> {
+        self.bufs.iter_mut().zip(&self.received)
+            .flat_map(|(buf, &(len, segment, addr))| buf[..len].chunks_mut(segment.max(1)).map(move |d| (d, addr)))
+    }
+}
+
+/// Datagrams for one batched send. Their buffers are kept when it is
+/// cleared, so refilling it does not allocate once it has warmed up.
+#[derive(Default)]
+pub struct SendBatch {
+    datagrams: Vec<(Vec<u8>, SocketAddr)>,
+    len: usize
+}
+
+impl SendBatch {
+    /// Adds a datagram to `addr` that `fill` writes into a spare buffer,
+    /// unless it returns false. Returns what `fill` did.
+    pub fn push_with(&mut self, addr: SocketAddr, fill: impl FnOnce(&mut Vec<u8>) -> bool) -> bool {
+        if self.len == self.datagrams.len() {
+            self.datagrams.push((Vec::new(), addr));
+        }
+        let (buf, to) = &mut self.datagrams[self.len];
+        *to = addr;
+        let filled = fill(buf);
+        self.len += usize::from(filled);
+        filled
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl Deref for BatchSocket {
+    type Target = UdpSocket;
+
+    fn deref(&self) -> &UdpSocket {
+        &self.sock
+    }
+}
+
+impl BatchSocket {
+    #[cfg(target_os = "linux")]
+    pub fn new(sock: UdpSocket) -> Self {
+        let fd = sock.as_raw_fd();
+        let gso = sys::supports_gso(fd);
+        let gro = sys::set_gro(fd, true);
+        info!("Batched UDP I/O: recvmmsg/sendmmsg, GSO {}, GRO {}", on_off(gso), on_off(gro));
+        BatchSocket { sock, mmsg: AtomicBool::new(true), gso: AtomicBool::new(gso), gro }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(sock: UdpSocket) -> Self {
+        info!("Batched UDP I/O is not available here, moving one datagram at a time");
+        BatchSocket { sock }
+    }
+
+    /// Buffers for `recv`, each taking a datagram of up to `len` bytes.
+    pub fn recv_batch(&self, len: usize) -> RecvBatch {
+        #[cfg(target_os = "linux")]
+        let (count, len) = (BATCH, if self.gro { GRO_BUF_LEN.max(len) } else { len });
+        #[cfg(not(target_os = "linux"))]
+        let count = 1;
+        RecvBatch { bufs: vec![vec![0; len]; count], received: Vec::with_capacity(count) }
+    }
+
+    /// Waits for at least one datagram and takes as many as are queued.
+    pub async fn recv(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.mmsg.load(Ordering::Relaxed) {
+            let fd = self.sock.as_raw_fd();
+            let RecvBatch { bufs, received } = batch;
+            match self.sock.async_io(Interest::READABLE, || sys::recv(fd, bufs, received)).await {
+                Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => self.without_mmsg(),
+                result => return result
+            }
+        }
+        let (len, addr) = self.sock.recv_from(&mut batch.bufs[0]).await?;
+        batch.received.clear();
+        batch.received.push((len, len, addr));
+        Ok(())
+    }
+
+    /// Sends all of `batch`. Datagrams the kernel refuses are dropped, and
+    /// the last refusal is returned.
+    pub async fn send(&self, batch: &SendBatch) -> io::Result<()> {
+        let datagrams = &batch.datagrams[..batch.len];
+        #[cfg(target_os = "linux")]
+        let (datagrams, result) = self.send_batched(datagrams).await;
+        #[cfg(not(target_os = "linux"))]
+        let result = Ok(());
+        self.send_each(datagrams).await.and(result)
+    }
+
+    /// Returns the datagrams left once batching turned out unsupported.
+    #[cfg(target_os = "linux")]
+    async fn send_batched<'a>(&self, datagrams: &'a [(Vec<u8>, SocketAddr)]) -> (&'a [(Vec<u8>, SocketAddr)], io::Result<()>) {
+        let fd = self.sock.as_raw_fd();
+        let mut result = Ok(());
+        let mut sent = 0;
+        while sent < datagrams.len() && self.mmsg.load(Ordering::Relaxed) {
+            let chunk = &datagrams[sent..datagrams.len().min(sent + BATCH)];
+            let gso = self.gso.load(Ordering::Relaxed);
+            match self.sock.async_io(Interest::WRITABLE, || sys::send(fd, chunk, gso)).await {
+                Ok(n) => sent += n,
+                Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => self.without_mmsg(),
+                // The route's device cannot offload segmentation (EIO), or a
+                // segment is over the path MTU (EINVAL, EMSGSIZE). Unlike a
+                // GSO segment, a single datagram that size gets fragmented.
+                Err(e) if gso && matches!(e.raw_os_error(), Some(libc::EIO | libc::EINVAL | libc::EMSGSIZE)) => {
+                    warn!("UDP GSO failed ({}), sending without it from now on", e);
+                    self.gso.store(false, Ordering::Relaxed);
+                },
+                // Leaves it to send_to to drop just the refused ones.
+                Err(e) => {
+                    debug!("Batched send failed: {}", e);
+                    result = self.send_each(chunk).await;
+                    sent += chunk.len();
+                }
+            }
+        }
+        (&datagrams[sent..], result)
+    }
+
+    async fn send_each(&self, datagrams: &[(Vec<u8>, SocketAddr)]) -> io::Result<()> {
+        let mut result = Ok(());
+        for (datagram, addr) in datagrams {
+            if let Err(e) = self.sock.send_to(datagram, addr).await {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Single receives cannot tell GRO segments apart, so GRO goes too.
+    #[cfg(target_os = "linux")]
+    fn without_mmsg(&self) {
+        if self.mmsg.swap(false, Ordering::Relaxed) {
+            warn!("recvmmsg/sendmmsg are not supported, moving one datagram at a time");
+            if self.gro {
+                sys::set_gro(self.sock.as_raw_fd(), false);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{io, mem, net::SocketAddr, os::fd::RawFd, ptr};
+    use libc::{c_int, c_void, iovec, mmsghdr, msghdr, sockaddr_storage, socklen_t};
+    use socket2::SockAddr;
+
+    use super::BATCH;
+
+    /// The most a UDP datagram carries, and so a GSO send.
+    const MAX_GSO_BYTES: usize = 65507;
+
+    /// Control messages fit in this, aligned for `cmsghdr`.
+    type Control = [u64; 8];
+
+    /// Kernels before 4.18 do not know the option.
+    pub fn supports_gso(fd: RawFd) -> bool {
+        let mut segment: c_int = 0;
+        let mut len = mem::size_of::<c_int>() as socklen_t;
+        unsafe { libc::getsockopt(fd, libc::SOL_UDP, libc::UDP_SEGMENT, &mut segment as *mut c_int as *mut c_void, &mut len) == 0 }
+    }
+
+    /// Kernels before 5.0 do not know the option.
+    pub fn set_gro(fd: RawFd, on: bool) -> bool {
+        let on = c_int::from(on);
+        unsafe { libc::setsockopt(fd, libc::SOL_UDP, libc::UDP_GRO, &on as *const c_int as *const c_void, mem::size_of::<c_int>() as socklen_t) == 0 }
+    }
+
+    pub fn recv(fd: RawFd, bufs: &mut [Vec<u8>], received: &mut Vec<(usize, usize, SocketAddr)>) -> io::Result<()> {
+        let count = bufs.len().min(BATCH);
+        let mut names: [sockaddr_storage; BATCH] = unsafe { mem::zeroed() };
+        let mut iovs: [iovec; BATCH] = unsafe { mem::zeroed() };
+        let mut controls: [Control; BATCH] = [[0; 8]; BATCH];
+        let mut msgs: [mmsghdr; BATCH] = unsafe { mem::zeroed() };
+        for (iov, buf) in iovs.iter_mut().zip(bufs.iter_mut()) {
+            *iov = iovec { iov_base: buf.as_mut_ptr() as *mut c_void, iov_len: buf.len() };
+        }
+        let (names_ptr, iovs_ptr, controls_ptr) = (names.as_mut_ptr(), iovs.as_mut_ptr(), controls.as_mut_ptr());
+        for (i, msg) in msgs.iter_mut().take(count).enumerate() {
+            let hdr = &mut msg.msg_hdr;
+            hdr.msg_name = unsafe { names_ptr.add(i) } as *mut c_void;
+            hdr.msg_namelen = mem::size_of::<sockaddr_storage>() as socklen_t;
+            hdr.msg_iov = unsafe { iovs_ptr.add(i) };
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = unsafe { controls_ptr.add(i) } as *mut c_void;
+            hdr.msg_controllen = mem::size_of::<Control>() as _;
+        }
+        let n = unsafe { libc::recvmmsg(fd, msgs.as_mut_ptr(), count as _, 0, ptr::null_mut()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        received.clear();
+        for (msg, name) in msgs.iter().zip(names).take(n as usize) {
+            let Some(addr) = unsafe { SockAddr::new(name, msg.msg_hdr.msg_namelen) }.as_socket() else { continue; };
+            let len = msg.msg_len as usize;
+            received.push((len, gro_segment(&msg.msg_hdr).unwrap_or(len), addr));
+        }
+        Ok(())
+    }
+
+    /// Segment size of a receive GRO coalesced.
+    fn gro_segment(hdr: &msghdr) -> Option<usize> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                    return Some(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const c_int) as usize);
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+
+    /// Sends up to `BATCH` of `datagrams` and returns how many went out.
+    /// With `gso`, runs of datagrams to one address go as one message the
+    /// kernel splits again. All but the last of a run must be equal in size.
+    pub fn send(fd: RawFd, datagrams: &[(Vec<u8>, SocketAddr)], gso: bool) -> io::Result<usize> {
+        let count = datagrams.len().min(BATCH);
+        let mut runs = [(0usize, 0usize); BATCH]; // first datagram, past the last
+        let mut messages = 0;
+        while messages == 0 || runs[messages - 1].1 < count {
+            let start = if messages == 0 { 0 } else { runs[messages - 1].1 };
+            let (first, addr) = &datagrams[start];
+            let segment = first.len();
+            let (mut end, mut bytes) = (start + 1, segment);
+            while gso && end < count && datagrams[end].1 == *addr && (1..=segment).contains(&datagrams[end].0.len())
+                && bytes + datagrams[end].0.len() <= MAX_GSO_BYTES {
+                bytes += datagrams[end].0.len();
+                end += 1;
+                if datagrams[end - 1].0.len() < segment { break; }
+            }
+            runs[messages] = (start, end);
+            messages += 1;
+        }
+
+        let mut names: [sockaddr_storage; BATCH] = unsafe { mem::zeroed() };
+        let mut iovs: [iovec; BATCH] = unsafe { mem::zeroed() };
+        let mut controls: [Control; BATCH] = [[0; 8]; BATCH];
+        let mut msgs: [mmsghdr; BATCH] = unsafe { mem::zeroed() };
+        for (iov, (datagram, _)) in iovs.iter_mut().zip(&datagrams[..count]) {
+            *iov = iovec { iov_base: datagram.as_ptr() as *mut c_void, iov_len: datagram.len() };
+        }
+        let (names_ptr, iovs_ptr, controls_ptr) = (names.as_mut_ptr(), iovs.as_mut_ptr(), controls.as_mut_ptr());
+        for (i, (msg, &(start, end))) in msgs.iter_mut().zip(&runs[..messages]).enumerate() {
+            let name = SockAddr::from(datagrams[start].1);
+            let hdr = &mut msg.msg_hdr;
+            unsafe {
+                ptr::copy_nonoverlapping(name.as_ptr() as *const u8, names_ptr.add(i) as *mut u8, name.len() as usize);
+                hdr.msg_name = names_ptr.add(i) as *mut c_void;
+                hdr.msg_iov = iovs_ptr.add(start);
+            }
+            hdr.msg_namelen = name.len();
+            hdr.msg_iovlen = (end - start) as _;
+            if end - start > 1 {
+                unsafe {
+                    hdr.msg_control = controls_ptr.add(i) as *mut c_void;
+                    hdr.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+                    let cmsg = libc::CMSG_FIRSTHDR(hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, datagrams[start].0.len() as u16);
+                }
+            }
+        }
+        let n = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), messages as _, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(runs[..n as usize].iter().map(|(start, end)| end - start).sum())
+    }
+}
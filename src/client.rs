@@ -1,22 +1,43 @@
-use crossbeam_channel::unbounded;
-use socket2::SockAddr;
-use tokio::{net::UdpSocket, sync::Mutex};
+use tokio::{net::UdpSocket, sync::Mutex, time};
 use std::{io::{Read, Write}, net::SocketAddr};
-use base64::prelude::*;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::net::Ipv4Addr;
-use x25519_dalek::{PublicKey, StaticSecret};
+use x25519_dalek::PublicKey;
 use std::process::Command;
-use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
-    Aes256Gcm, Nonce};
 
-use crate::config::ClientConfiguration;
-use crate::udp::{UDPVpnPacket, UDPVpnHandshake, UDPSerializable};
+use crate::batch::{BatchSocket, SendBatch, BATCH};
+use crate::config::{CipherSuite, ClientConfiguration, RekeyConfig};
+use crate::control::{self, PeerInfo, QueueDrops, Request, Response};
+use crate::cookie::CookieGenerator;
+use crate::noise::{self, Initiator, Keypair};
+use crate::queue::{Pool, Queue};
+use crate::session::{Session, Sessions};
+use crate::udp::{self, Message, UDPHandshakePayload, UDPHandshakeResponsePayload, UDPSerializable};
 use network_interface::NetworkInterface;
 use network_interface::NetworkInterfaceConfig;
 
+const HANDSHAKE_RETRY_SEC: u64 = 5;
+const KEEPALIVE_TIMEOUT_SEC: u64 = 5;
+
+struct Tunnel {
+    handshake: Option<(Initiator, u32, Instant)>, // with the index sent in it
+    sessions: Sessions,
+    cookie: CookieGenerator,
+    handshake_at: Option<Instant> // last completed
+}
+
+impl Tunnel {
+    /// A server that stops answering keepalives has most likely lost the
+    /// session, e.g. after a restart, so a fresh handshake is started.
+    fn needs_handshake(&self, cfg: &RekeyConfig) -> bool {
+        let retry = self.handshake.as_ref().is_none_or(|(_, _, sent)| sent.elapsed() >= Duration::from_secs(HANDSHAKE_RETRY_SEC));
+        let unresponsive = !self.sessions.is_responsive(Duration::from_secs(KEEPALIVE_TIMEOUT_SEC));
+        retry && (unresponsive || self.sessions.current().is_none_or(|s| s.rekey_due(cfg)))
+    }
+}
+
 fn configure_routes(endpoint_ip: &str, s_interface: Option<&str>) {
     let interfaces = NetworkInterface::show().unwrap();
 
@@ -25,7 +46,7 @@ fn configure_routes(endpoint_ip: &str, s_interface: Option<&str>) {
         .min_by(|x, y| x.index.cmp(&y.index))
         .unwrap();
 
-    let inter_name = if s_interface.is_some() { s_interface.unwrap() } else { &net_inter.name };
+    let inter_name = s_interface.unwrap_or(&net_inter.name);
 
     info!("Main network interface: {:?}", inter_name);
 
@@ -77,8 +98,10 @@ pub async fn client_mode(client_config: ClientConfiguration, s_interface: Option
     info!("Starting client...");
     info!("s_interface: {:?}", s_interface);
 
+    // Not connected: the kernel then picks the source address per packet,
+    // so the tunnel keeps working when the client moves between networks.
     let sock = UdpSocket::bind("0.0.0.0:25565").await.unwrap();
-    sock.connect(&client_config.server.endpoint).await.unwrap();
+    let s_a: SocketAddr = client_config.server.endpoint.parse().unwrap();
 
     let mut config = tun2::Configuration::default();
     config.address(&client_config.client.address)
@@ -90,110 +113,205 @@ pub async fn client_mode(client_config: ClientConfiguration, s_interface: Option
     let dev = tun2::create(&config).unwrap();
     let (mut dev_reader, mut dev_writer) = dev.split();
 
-    let sock_rec = Arc::new(sock);
+    let sock_rec = Arc::new(BatchSocket::new(sock));
     let sock_snd = sock_rec.clone();
 
-    let (tx, rx) = unbounded::<Vec<u8>>();
-    let (dx, mx) = unbounded::<Vec<u8>>();
-
-    let cipher_shared = Arc::new(Mutex::new(None));
+    let queues = client_config.queues.clone();
+    let (tx, mut rx) = Queue::<Vec<u8>>::new("tun", queues.tun, queues.policy);
+    let (dx, mut mx) = Queue::<Vec<u8>>::new("socket", queues.socket, queues.policy);
+    let (tun_queue, sock_queue) = (tx.clone(), dx.clone());
+    let pool = Pool::new(queues.tun);
+    let pool_tw = pool.clone();
 
     tokio::spawn(async move {
-        while let Ok(bytes) = rx.recv() {
+        while let Some(bytes) = rx.recv().await {
             //info!("Write to tun {:?}", hex::encode(&bytes));
             dev_writer.write_all(&bytes).unwrap();
+            pool_tw.give(bytes);
         }
     });
 
     tokio::spawn(async move {
         let mut buf = vec![0; 8192];
         while let Ok(n) = dev_reader.read(&mut buf) {
-            dx.send(buf[..n].to_vec()).unwrap();
+            dx.push(buf[..n].to_vec()).await;
         }
     });
 
-    let s_a: SocketAddr = client_config.server.endpoint.parse().unwrap();
     #[cfg(target_os = "linux")]
     configure_routes(&s_a.ip().to_string(), s_interface);
 
-    let priv_key = BASE64_STANDARD.decode(client_config.client.private_key).unwrap();
+    let keypair = Arc::new(Keypair::from_base64(&client_config.client.private_key).expect("Bad client private key"));
+    let server_key = PublicKey::from(noise::decode_key(&client_config.server.public_key).expect("Bad server public key"));
+    let request_ip = client_config.client.address.parse::<Ipv4Addr>().unwrap();
+    let cipher_suites = client_config.client.cipher_suites.clone();
+    let psk = client_config.server.preshared_key.as_deref().map(|k| noise::decode_key(k).expect("Bad preshared key"));
+    let tunnel = Arc::new(Mutex::new(Tunnel { handshake: None, sessions: Sessions::default(), cookie: CookieGenerator::new(&server_key), handshake_at: None }));
     
-    let cipher_shared_clone = cipher_shared.clone();
+    #[cfg(unix)]
+    if let Some(listener) = control::listen(&client_config.control.socket) {
+        let tunnel_ctl = tunnel.clone();
+        let public_key = client_config.server.public_key.clone();
+        tokio::spawn(control::serve(listener, move |request| {
+            let tunnel = tunnel_ctl.clone();
+            let public_key = public_key.clone();
+            let queue_drops = QueueDrops { tun: tun_queue.drops(), socket: sock_queue.drops() };
+            async move {
+                let Request::Show = request else { return Response::Error(String::from("The client only answers show requests")); };
+                let tn = tunnel.lock().await;
+                Response::Peers(vec![PeerInfo {
+                    public_key,
+                    address: None,
+                    endpoint: tn.sessions.is_established().then_some(s_a),
+                    last_handshake_sec: tn.handshake_at.map(|t| t.elapsed().as_secs()),
+                    traffic: tn.sessions.traffic(),
+                    queue_drops: Some(queue_drops)
+                }])
+            }
+        }));
+    }
+
+    let rekey = client_config.rekey.clone();
+    let tunnel_rcv = tunnel.clone();
+    let sock_cfm = sock_rec.clone();
     tokio::spawn(async move {
-        let mut buf = vec![0; 4096];
+        let mut batch = sock_rec.recv_batch(4096);
 
+        let mut reply = Vec::new();
+        let mut malformed: u64 = 0;
         loop {
-            if let Ok(l) = sock_rec.recv(&mut buf).await {
-                let mut s_cipher = cipher_shared_clone.lock().await;
-                match buf.first() {
-                    Some(h) => {
-                        match h {
-                            0 => {
-                                let handshake = UDPVpnHandshake::deserialize(&(buf[..l].to_vec()));
-                                let mut k = [0u8; 32];
-                                for (&x, p) in handshake.public_key.iter().zip(k.iter_mut()) {
-                                    *p = x;
-                                }
-                                let mut k1 = [0u8; 32];
-                                for (&x, p) in priv_key.iter().zip(k1.iter_mut()) {
-                                    *p = x;
-                                }
-                                *s_cipher = Some(StaticSecret::from(k1)
-                                    .diffie_hellman(&PublicKey::from(k)));
-                            }, // handshake
-                            1 => {
-                                let wrapped_packet = UDPVpnPacket::deserialize(&(buf[..l].to_vec()));
-                                if s_cipher.is_some() {
-                                    let aes = Aes256Gcm::new(s_cipher.as_ref().unwrap().as_bytes().into());
-                                    let nonce = Nonce::clone_from_slice(&wrapped_packet.nonce);
-                                    match aes.decrypt(&nonce, &wrapped_packet.data[..]) {
-                                        Ok(decrypted) => { let _ = tx.send(decrypted); },
-                                        Err(error) => error!("Decryption error! {:?}", error)
+            if sock_rec.recv(&mut batch).await.is_err() { continue; }
+            for (datagram, from) in batch.datagrams() {
+                    if from != s_a { continue; }
+                    let message = match udp::decode(datagram) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            malformed += 1;
+                            if malformed.is_power_of_two() {
+                                warn!("Dropped malformed datagram: {} ({} total)", e, malformed);
+                            }
+                            continue;
+                        }
+                    };
+                    let mut tn = tunnel_rcv.lock().await;
+                    match message {
+                        Message::HandshakeResponse(response) => {
+                            let Some((_, index, _)) = tn.handshake.as_ref().filter(|(_, index, _)| *index == response.receiver) else {
+                                warn!("Unexpected handshake response");
+                                continue;
+                            };
+                            let index = *index;
+                            let (initiator, _, _) = tn.handshake.take().unwrap();
+                            let (keys, payload) = match initiator.consume_response(&response, psk.as_ref()) {
+                                Ok(r) => r,
+                                Err(e) => { error!("Bad handshake response: {}", e); continue; }
+                            };
+                            let suite = UDPHandshakeResponsePayload::deserialize(&payload).ok()
+                                .and_then(|p| CipherSuite::from_id(p.cipher_suite))
+                                .filter(|s| cipher_suites.contains(s));
+                            match suite {
+                                Some(suite) => {
+                                    info!("Handshake completed, cipher suite {:?}", suite);
+                                    tn.sessions.rotate(Session::new(keys, suite, index, response.sender));
+                                    tn.handshake_at = Some(Instant::now());
+                                    // A keepalive confirms the new keys to the server.
+                                    if let Some(confirmation) = tn.sessions.keepalive(&rekey) {
+                                        let _ = sock_cfm.send_to(&confirmation, s_a).await;
                                     }
-                                } else {
-                                    warn!("There is no static_secret");
-                                }
-                            }, // payload
-                            2 => info!("Got keepalive packet"),
-                            _ => error!("Unexpected header value.")
+                                },
+                                None => error!("Server selected an unsupported cipher suite")
+                            }
+                        },
+                        Message::CookieReply(reply) => {
+                            match tn.cookie.consume_reply(&reply) {
+                                Ok(()) => {
+                                    info!("Server is under load, retrying handshake with a cookie");
+                                    tn.handshake = None;
+                                },
+                                Err(e) => warn!("Bad cookie reply: {}", e)
+                            }
+                        },
+                        Message::Handshake(_) => warn!("Unexpected handshake initiation"),
+                        Message::Packet(mut wrapped_packet) => {
+                            match tn.sessions.decrypt(&mut wrapped_packet, &rekey) {
+                                Ok([]) => {
+                                    if tn.sessions.keepalive_reply(&mut reply, &rekey) {
+                                        let _ = sock_cfm.send_to(&reply, s_a).await;
+                                    }
+                                    debug!("Keepalive, rtt {:?}", tn.sessions.rtt());
+                                },
+                                // Copied out of the receive buffers for the tun writer.
+                                Ok(decrypted) => {
+                                    let mut buf = pool.take();
+                                    buf.extend_from_slice(decrypted);
+                                    tx.push(buf).await;
+                                },
+                                // Counted in the traffic, and not authenticated.
+                                Err(e) => debug!("Dropped packet: {}", e)
+                            }
                         }
+                    }
+                    drop(tn);
+            }
+        }
+    });
+
+    let rekey = client_config.rekey.clone();
+    let keepalive = Duration::from_secs(client_config.server.keepalive.into());
+    let suite_ids = client_config.client.cipher_suites.iter().map(|s| s.id()).collect::<Vec<u8>>();
+    let tunnel_hnd = tunnel.clone();
+    let sock_hnd = sock_snd.clone();
+    tokio::spawn(async move {
+        loop {
+            let mut tn = tunnel_hnd.lock().await;
+            tn.sessions.expire(&rekey);
+            if tn.needs_handshake(&rekey) {
+                let payload = UDPHandshakePayload { timestamp: noise::timestamp(), request_ip, cipher_suites: suite_ids.clone() };
+                match Initiator::new(&keypair, &server_key, &payload.serialize()) {
+                    Ok((initiator, mut handshake)) => {
+                        handshake.sender = rand::random::<u32>();
+                        tn.cookie.stamp(&mut handshake);
+                        tn.handshake = Some((initiator, handshake.sender, Instant::now()));
+                        let _ = sock_hnd.send_to(&handshake.serialize(), s_a).await;
                     },
-                    None => error!("There is no header.")
+                    Err(e) => error!("Failed to start handshake: {}", e)
                 }
-                drop(s_cipher);
             }
+            let due = tn.sessions.last_probe().is_none_or(|sent| sent.elapsed() >= keepalive);
+            if !keepalive.is_zero() && tn.sessions.is_established() && due {
+                if let Some(packet) = tn.sessions.keepalive(&rekey) {
+                    let _ = sock_hnd.send_to(&packet, s_a).await;
+                }
+            }
+            drop(tn);
+            time::sleep(time::Duration::from_secs(1)).await;
         }
     });
 
-    let pkey = BASE64_STANDARD.decode(client_config.client.public_key).unwrap();
-    let handshake = UDPVpnHandshake{ public_key: pkey, request_ip: client_config.client.address.parse::<Ipv4Addr>().unwrap() };
-    let mut nz = 0;
-    while nz < 25 {
-        sock_snd.send(&handshake.serialize()).await.unwrap();
-        nz += 1
-    }
-    //sock_snd.send(&handshake.serialize()).await.unwrap();
-
-    let s_cipher = cipher_shared.clone();
-    loop {
-        if let Ok(bytes) = mx.recv() {
-            let s_c = s_cipher.lock().await;
-            
-            if s_c.is_some() {
-                let aes = Aes256Gcm::new(s_c.as_ref().unwrap().as_bytes().into());
-                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-                let ciphered_data = aes.encrypt(&nonce, &bytes[..]);
-                
-                if let Ok(ciphered_d) = ciphered_data {
-                    let vpn_packet = UDPVpnPacket{ data: ciphered_d, nonce: nonce.to_vec()};
-                    let serialized_data = vpn_packet.serialize();
-                    sock_snd.send(&serialized_data).await.unwrap();
-                } else {
+    let rekey = client_config.rekey.clone();
+    let mut pending = Vec::with_capacity(BATCH);
+    let mut out = SendBatch::default();
+
+    // Packets the tun device queued meanwhile go out in the same batch.
+    while mx.recv_many(&mut pending, BATCH).await > 0 {
+        let mut tn = tunnel.lock().await;
+        
+        if tn.sessions.is_established() {
+            for bytes in pending.drain(..) {
+                if !out.push_with(s_a, |buf| tn.sessions.encrypt(&bytes, buf, &rekey)) {
                     error!("Socket encryption failed.");
                 }
-            } else {
-                warn!("There is no shared_secret in main loop");
             }
+            drop(tn);
+            // Sends fail for a moment while the network changes, e.g.
+            // when moving from Wi-Fi to LTE; the session survives that.
+            if let Err(e) = sock_snd.send(&out).await {
+                warn!("Failed to send packet: {}", e);
+            }
+            out.clear();
+        } else {
+            pending.clear();
+            warn!("There is no shared_secret in main loop");
         }
     }
-}
\ No newline at end of file
+}
@@ -3,9 +3,13 @@ use serde_derive::Serialize;
 use serde_derive::Deserialize;
 use std::str::FromStr;
 use x25519_dalek::{StaticSecret, PublicKey};
-use rand::{rngs::StdRng, SeedableRng};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
 use base64::prelude::*;
 
+use crate::noise;
+use crate::ratelimit::TokenBucket;
+use crate::routing::Cidr;
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct ServerInterface {
     pub bind_address: String,
@@ -13,15 +17,159 @@ pub struct ServerInterface {
     pub private_key: String,
     pub public_key: String,
     pub broadcast_mode: bool,
-    pub keepalive: u8
+    pub keepalive: u8,
+    /// Accepted cipher suites, most preferred first
+    #[serde(default = "default_cipher_suites")]
+    pub cipher_suites: Vec<CipherSuite>
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct ServerPeer {
     pub public_key: String,
-    pub ip: Ipv4Addr
+    pub ip: Ipv4Addr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<String>,
+    /// Further ranges routed to this peer, e.g. the LAN behind a site router
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_ips: Vec<Cidr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<Quota>
+}
+
+/// Bandwidth cap of one peer in bits per second of tunnelled packets, 0
+/// meaning unlimited. Ingress is what the peer sends, egress what it is
+/// sent. Packets over the limit are dropped. `burst_bytes` defaults to one
+/// second's worth, and is raised to the largest IP packet if below it, or
+/// such packets could never pass.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RateLimit {
+    pub ingress_bps: u64,
+    pub egress_bps: u64,
+    pub burst_bytes: u64
+}
+
+/// Data cap of one peer, counting bytes both ways on the wire. Once it is
+/// used up, the server refuses the peer's handshakes or throttles it to
+/// `throttle_bps` until the period ends.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Quota {
+    pub bytes: u64,
+    #[serde(default)]
+    pub period: QuotaPeriod,
+    #[serde(default)]
+    pub policy: QuotaPolicy,
+    #[serde(default = "default_throttle_bps")]
+    pub throttle_bps: u64
+}
+
+/// Monthly quotas start over on the first of each month, UTC.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub enum QuotaPeriod {
+    #[default]
+    Monthly,
+    Total
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub enum QuotaPolicy {
+    #[default]
+    Refuse,
+    Throttle
+}
+
+/// The largest IP packet.
+const MAX_PACKET_BYTES: u64 = 65535;
+
+fn default_throttle_bps() -> u64 {
+    1_000_000
+}
+
+impl Quota {
+    /// The limit a throttled peer gets in both directions.
+    pub fn throttled(&self) -> RateLimit {
+        RateLimit { ingress_bps: self.throttle_bps, egress_bps: self.throttle_bps, burst_bytes: 0 }
+    }
+}
+
+impl RateLimit {
+    /// Bucket of bytes for a direction limited to `bps`, if it is limited.
+    pub fn bucket(&self, bps: u64) -> Option<TokenBucket> {
+        let rate = bps / 8;
+        let burst = if self.burst_bytes > 0 { self.burst_bytes } else { rate };
+        (bps > 0).then(|| TokenBucket::new(rate, burst.max(MAX_PACKET_BYTES)))
+    }
+}
+
+impl ServerPeer {
+    /// The peer's own address followed by its allowed ranges.
+    pub fn routes(&self) -> impl Iterator<Item = Cidr> + '_ {
+        std::iter::once(Cidr::host(self.ip.into())).chain(self.allowed_ips.iter().copied())
+    }
+}
+
+/// Rejects peers the server could not serve as configured, rather than
+/// finding out when they handshake or their packets go astray. A range may
+/// be routed to one peer only, and may not take another peer's address.
+pub fn check_peers(peers: &[ServerPeer]) -> Result<(), String> {
+    for (i, peer) in peers.iter().enumerate() {
+        if let Some(key) = &peer.preshared_key {
+            noise::decode_key(key).map_err(|e| format!("Peer {} has a bad preshared key: {}", peer.ip, e))?;
+        }
+        for other in &peers[i + 1..] {
+            for (a, b) in [(peer, other), (other, peer)] {
+                if let Some(range) = a.allowed_ips.iter().find(|r| r.contains(b.ip.into())) {
+                    return Err(format!("Allowed IPs {} of peer {} cover peer {}", range, a.ip, b.ip));
+                }
+            }
+            if let Some(range) = peer.routes().find(|r| other.routes().any(|o| o == *r)) {
+                return Err(format!("Peers {} and {} both route {}", peer.ip, other.ip, range));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Peers of `old` that are gone or changed in `new`, and peers of `new`
+/// that are not in `old` as they are. A changed peer is in both lists.
+pub fn diff_peers<'a>(old: &'a [ServerPeer], new: &'a [ServerPeer]) -> (Vec<&'a ServerPeer>, Vec<&'a ServerPeer>) {
+    let removed = old.iter().filter(|p| !new.contains(p)).collect();
+    let added = new.iter().filter(|p| !old.contains(p)).collect();
+    (removed, added)
+}
+
+/// AEAD used for the data packets of a session. The handshake itself always
+/// uses AES-256-GCM.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum CipherSuite {
+    Aes256Gcm,
+    ChaCha20Poly1305
+}
+
+impl CipherSuite {
+    pub fn id(&self) -> u8 {
+        match self {
+            CipherSuite::Aes256Gcm => 1,
+            CipherSuite::ChaCha20Poly1305 => 2
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(CipherSuite::Aes256Gcm),
+            2 => Some(CipherSuite::ChaCha20Poly1305),
+            _ => None
+        }
+    }
 }
 
+fn default_cipher_suites() -> Vec<CipherSuite> {
+    vec![CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305]
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum ObfsProtocol {
     FakeDNS,
@@ -40,7 +188,28 @@ pub struct ServerConfiguration {
     pub interface: ServerInterface,
     pub peers: Vec<ServerPeer>,
     pub obfs: ObfsConfig,
-    pub dns: DNSConfig
+    pub dns: DNSConfig,
+    #[serde(default)]
+    pub rekey: RekeyConfig,
+    #[serde(default)]
+    pub handshake: HandshakeConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub broadcast: BroadcastConfig,
+    #[serde(default)]
+    pub forwarding: ForwardingConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    /// Prometheus exporter, off unless the section is present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<MetricsConfig>,
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    #[serde(default)]
+    pub data_plane: DataPlaneConfig,
+    #[serde(default)]
+    pub queues: QueueConfig
 }
 
 impl ServerConfiguration {
@@ -53,15 +222,193 @@ impl ServerConfiguration {
                 private_key: BASE64_STANDARD.encode(secret.as_bytes()), 
                 public_key: BASE64_STANDARD.encode(PublicKey::from(&secret).as_bytes()),
                 broadcast_mode, 
-                keepalive 
+                keepalive,
+                cipher_suites: default_cipher_suites()
             }, 
             peers: Vec::new(), 
             obfs: ObfsConfig { protocol: obfs_type }, 
-            dns: DNSConfig { enabled: false, net_name: String::from_str("fridah.vpn").unwrap(), entries: Vec::new() } 
+            dns: DNSConfig { enabled: false, net_name: String::from_str("fridah.vpn").unwrap(), entries: Vec::new() },
+            rekey: RekeyConfig::default(),
+            handshake: HandshakeConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            broadcast: BroadcastConfig::default(),
+            forwarding: ForwardingConfig::default(),
+            control: ControlConfig::default(),
+            metrics: None,
+            quotas: QuotaConfig::default(),
+            data_plane: DataPlaneConfig::default(),
+            queues: QueueConfig::default()
         }
     }
 }
 
+/// Handshake flood protection. Once more than `under_load_threshold`
+/// initiations arrive within a second, the server stops running the DH for
+/// initiations without a valid cookie and sends a cookie reply instead.
+/// It leaves this mode `under_load_cooldown_sec` after the last burst.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct HandshakeConfig {
+    pub under_load_threshold: u32,
+    pub under_load_cooldown_sec: u64
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        HandshakeConfig { under_load_threshold: 128, under_load_cooldown_sec: 5 }
+    }
+}
+
+/// When the server forgets a peer: after `idle_sec` without an authenticated
+/// packet, or `expiry_sec` after its last handshake. Clients rekey well
+/// before that, so `expiry_sec` only catches peers that stopped doing so.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    pub idle_sec: u64,
+    pub expiry_sec: u64
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig { idle_sec: 180, expiry_sec: 600 }
+    }
+}
+
+/// Limits packets fanned out in `broadcast_mode`. Each one is sent to every
+/// other peer, so the limit keeps a peer from using the server as an
+/// amplifier.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct BroadcastConfig {
+    pub packets_per_sec: u64,
+    pub burst: u64
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        BroadcastConfig { packets_per_sec: 100, burst: 200 }
+    }
+}
+
+/// Traffic between peers. With `in_process` the server re-encrypts it
+/// straight to the destination peer instead of routing it through the tun
+/// device, and so past the kernel's FORWARD chain. Off by default, so
+/// firewall rules keep applying. `isolate_peers` drops it on either path,
+/// broadcasts included.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ForwardingConfig {
+    pub in_process: bool,
+    pub isolate_peers: bool
+}
+
+/// Unix socket `frida_vpn ctl` and `show` reach a running server or client
+/// through. Only the owner may connect.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct ControlConfig {
+    pub socket: String
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig { socket: String::from("/run/frida_vpn.sock") }
+    }
+}
+
+impl ControlConfig {
+    /// A client may run on the same host as a server.
+    pub fn client() -> Self {
+        ControlConfig { socket: String::from("/run/frida_vpn_client.sock") }
+    }
+}
+
+/// Workers encrypting and decrypting data packets, each serving its own
+/// share of the peers. 0 starts one per CPU core.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DataPlaneConfig {
+    pub workers: usize
+}
+
+/// Packets each internal queue holds: `tun` those waiting for the tun
+/// device, `socket` those waiting to go out on the socket and `worker`
+/// those waiting for each data plane worker, on the server only.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct QueueConfig {
+    pub tun: usize,
+    pub socket: usize,
+    pub worker: usize,
+    pub policy: DropPolicy
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig { tun: 1024, socket: 1024, worker: 1024, policy: DropPolicy::default() }
+    }
+}
+
+/// What a full queue does with one more packet. Either way, packets one
+/// worker forwards to another are dropped, so workers never wait on each
+/// other.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub enum DropPolicy {
+    /// Drops the packet, as a congested link would
+    #[default]
+    TailDrop,
+    /// Waits for room, which holds up reading the socket or tun device
+    Block
+}
+
+/// Where the bytes peers used against their quotas are kept across
+/// restarts. Usage since the last save is lost if the server crashes.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub state_file: String,
+    pub save_interval_sec: u64
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig { state_file: String::from("/var/lib/frida_vpn/usage.yaml"), save_interval_sec: 60 }
+    }
+}
+
+/// HTTP listener serving Prometheus metrics at `/metrics`. Per-peer series
+/// are labelled with the peer's tunnel address.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub listen: String
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig { listen: String::from("127.0.0.1:9586") }
+    }
+}
+
+/// Session key lifetime. The client starts a new handshake once any limit
+/// is reached; sessions past twice the limits are refused by both sides.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct RekeyConfig {
+    pub interval_sec: u64,
+    pub max_bytes: u64,
+    pub max_packets: u64,
+    /// How long the previous session still decrypts after a rotation
+    pub overlap_sec: u64
+}
+
+impl Default for RekeyConfig {
+    fn default() -> Self {
+        RekeyConfig { interval_sec: 120, max_bytes: 1 << 34, max_packets: 1 << 30, overlap_sec: 10 }
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct DNSConfig {
     enabled: bool,
@@ -79,37 +426,60 @@ pub struct DNSEntry {
 pub struct ClientInterface {
     pub private_key: String,
     pub public_key: String,
-    pub address: String
+    pub address: String,
+    /// Supported cipher suites. Hosts without AES instructions should list
+    /// only `ChaCha20Poly1305`.
+    #[serde(default = "default_cipher_suites")]
+    pub cipher_suites: Vec<CipherSuite>
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct EndpointInterface {
     pub public_key: String,
     pub endpoint: String,
-    pub keepalive: u8
+    pub keepalive: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<String>
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct ClientConfiguration {
     pub client: ClientInterface,
-    pub server: EndpointInterface
+    pub server: EndpointInterface,
+    #[serde(default)]
+    pub rekey: RekeyConfig,
+    #[serde(default = "ControlConfig::client")]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub queues: QueueConfig
 }
 
 impl ClientConfiguration {
-    pub fn default(endpoint: &str, keepalive: u8, public_key: &str, internal_address: &str) -> Self {
+    pub fn default(endpoint: &str, keepalive: u8, public_key: &str, internal_address: &str, preshared_key: Option<String>) -> Self {
         let mut csprng = StdRng::from_entropy();
         let secret = StaticSecret::random_from_rng(&mut csprng);
         ClientConfiguration { 
             client: ClientInterface { 
                 private_key: BASE64_STANDARD.encode(secret.as_bytes()), 
                 public_key: BASE64_STANDARD.encode(PublicKey::from(&secret).as_bytes()),
-                address: String::from_str(internal_address).unwrap() 
+                address: String::from_str(internal_address).unwrap(),
+                cipher_suites: default_cipher_suites()
             }, 
             server: EndpointInterface { 
                 public_key: String::from_str(public_key).unwrap(), 
                 endpoint: String::from_str(endpoint).unwrap(),
-                keepalive
-            } 
+                keepalive,
+                preshared_key
+            },
+            rekey: RekeyConfig::default(),
+            control: ControlConfig::client(),
+            queues: QueueConfig::default()
         }
     }
-}
\ No newline at end of file
+}
+
+pub fn generate_preshared_key() -> String {
+    let mut key = [0u8; 32];
+    StdRng::from_entropy().fill_bytes(&mut key);
+    BASE64_STANDARD.encode(key)
+}
@@ -0,0 +1,181 @@
+use std::{fmt, fs, future::Future, io, net::{IpAddr, Ipv4Addr, SocketAddr}};
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::config::ServerPeer;
+use crate::session::Traffic;
+
+// The control socket speaks newline-delimited JSON: one request line, one
+// response line, any number of times per connection.
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Sessions,
+    Stats,
+    /// Answered by the client as well
+    Show,
+    /// Added to the config file as well, so it survives a restart
+    AddPeer { peer: ServerPeer },
+    RemovePeer { ip: Ipv4Addr },
+    /// Tears down the session only. The peer may handshake again.
+    Kick { ip: IpAddr }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Sessions(Vec<SessionInfo>),
+    Stats(Stats),
+    Peers(Vec<PeerInfo>),
+    Ok,
+    Error(String)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionInfo {
+    pub ip: IpAddr,
+    pub endpoint: SocketAddr,
+    pub last_handshake_sec: u64,
+    pub connected_sec: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<u64>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Stats {
+    pub uptime_sec: u64,
+    pub peers: usize,
+    pub sessions: usize,
+    pub handshakes: u64,
+    pub cookie_replies: u64,
+    pub malformed: u64,
+    pub bad_mac: u64,
+    pub spoofed: u64,
+    #[serde(default)]
+    pub queue_drops: u64
+}
+
+/// Traffic with a configured peer. On the client, the peer is the server.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerInfo {
+    pub public_key: String,
+    /// The peer's tunnel address, known to the server only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Ipv4Addr>,
+    pub endpoint: Option<SocketAddr>,
+    pub last_handshake_sec: Option<u64>,
+    #[serde(flatten)]
+    pub traffic: Traffic,
+    /// Packets the client dropped on its full queues
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_drops: Option<QueueDrops>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueueDrops {
+    pub tun: u64,
+    pub socket: u64
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "peer: {}", self.public_key)?;
+        if let Some(address) = self.address {
+            writeln!(f, "  address: {}", address)?;
+        }
+        match self.endpoint {
+            Some(endpoint) => writeln!(f, "  endpoint: {}", endpoint)?,
+            None => writeln!(f, "  endpoint: (not connected)")?
+        }
+        if let Some(sec) = self.last_handshake_sec {
+            writeln!(f, "  latest handshake: {}s ago", sec)?;
+        }
+        let t = &self.traffic;
+        writeln!(f, "  received: {} in {} packets, {} failed to decrypt", bytes(t.rx_bytes), t.rx_packets, t.decrypt_failures)?;
+        write!(f, "  sent: {} in {} packets", bytes(t.tx_bytes), t.tx_packets)?;
+        if let Some(drops) = &self.queue_drops {
+            write!(f, "\n  dropped on full queues: {} to the tun device, {} to the socket", drops.tun, drops.socket)?;
+        }
+        Ok(())
+    }
+}
+
+fn bytes(n: u64) -> String {
+    match n {
+        0..=1023 => format!("{} B", n),
+        1024..=1048575 => format!("{:.2} KiB", n as f64 / 1024.0),
+        1048576..=1073741823 => format!("{:.2} MiB", n as f64 / 1048576.0),
+        _ => format!("{:.2} GiB", n as f64 / 1073741824.0)
+    }
+}
+
+impl fmt::Display for SessionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}, last handshake {}s ago, connected for {}s", self.ip, self.endpoint, self.last_handshake_sec, self.connected_sec)?;
+        if let Some(rtt) = self.rtt_ms {
+            write!(f, ", rtt {}ms", rtt)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "uptime: {}s", self.uptime_sec)?;
+        writeln!(f, "peers: {} configured, {} connected", self.peers, self.sessions)?;
+        writeln!(f, "handshakes: {} accepted, {} cookie replies", self.handshakes, self.cookie_replies)?;
+        write!(f, "dropped: {} malformed, {} bad mac1, {} spoofed, {} on full queues", self.malformed, self.bad_mac, self.spoofed, self.queue_drops)
+    }
+}
+
+/// Opens the control socket, replacing one left over from an earlier run.
+/// Only the owner may connect.
+pub fn listen(socket: &str) -> Option<UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+    let _ = fs::remove_file(socket);
+    let listener = match UnixListener::bind(socket) {
+        Ok(listener) => listener,
+        Err(e) => { error!("Failed to open control socket {}: {}", socket, e); return None; }
+    };
+    if let Err(e) = fs::set_permissions(socket, fs::Permissions::from_mode(0o600)) {
+        error!("Failed to restrict control socket {}: {}", socket, e);
+    }
+    info!("Control socket listening at {}", socket);
+    Some(listener)
+}
+
+/// Answers requests on `listener` with `handle` until the process stops.
+pub async fn serve<F, R>(listener: UnixListener, handle: F)
+where F: Fn(Request) -> R + Clone + Send + 'static, R: Future<Output = Response> + Send {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else { continue; };
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let response = match serde_json::from_str::<Request>(&line) {
+                    Ok(request) => handle(request).await,
+                    Err(e) => Response::Error(format!("Bad request: {}", e))
+                };
+                let mut line = serde_json::to_string(&response).unwrap();
+                line.push('\n');
+                if writer.write_all(line.as_bytes()).await.is_err() { break; }
+            }
+        });
+    }
+}
+
+/// Sends one request to the process listening on `socket`.
+pub async fn request(socket: &str, request: &Request) -> io::Result<Response> {
+    let mut stream = BufReader::new(UnixStream::connect(socket).await?);
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.get_mut().write_all(line.as_bytes()).await?;
+    line.clear();
+    stream.read_line(&mut line).await?;
+    Ok(serde_json::from_str(&line)?)
+}
@@ -0,0 +1,166 @@
+use std::{net::{IpAddr, SocketAddr}, time::{Duration, Instant}};
+use chacha20poly1305::{aead::{Aead, KeyInit, Payload}, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use x25519_dalek::PublicKey;
+use zeroize::Zeroize;
+
+use crate::config::HandshakeConfig;
+use crate::noise::NoiseError;
+use crate::udp::{UDPCookieReply, UDPSerializable, UDPVpnHandshake, MAC_LEN};
+
+// Handshake flood protection in the style of WireGuard. Every initiation
+// carries mac1, keyed to the server's public key, which the server checks
+// before doing any DH. While under load the server also requires mac2,
+// keyed to a cookie bound to the sender's address; initiations without one
+// only get an encrypted cookie back, which costs no per-client state.
+const MAC1_LABEL: &[u8] = b"mac1----";
+const COOKIE_LABEL: &[u8] = b"cookie--";
+const COOKIE_SECRET_LIFETIME: Duration = Duration::from_secs(120);
+const COOKIE_LIFETIME: Duration = Duration::from_secs(115);
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn label_key(label: &[u8], public: &PublicKey) -> [u8; 32] {
+    Sha256::new().chain_update(label).chain_update(public.as_bytes()).finalize().into()
+}
+
+fn keyed_mac(key: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    parts.iter().for_each(|p| mac.update(p));
+    mac
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> [u8; MAC_LEN] {
+    let mut out = [0u8; MAC_LEN];
+    out.copy_from_slice(&keyed_mac(key, parts).finalize().into_bytes()[..MAC_LEN]);
+    out
+}
+
+fn verify(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+    keyed_mac(key, &[data]).verify_truncated_left(tag).is_ok()
+}
+
+/// Server side: validates macs and issues cookies.
+pub struct CookieChecker {
+    mac1_key: [u8; 32],
+    cookie_key: [u8; 32],
+    secret: [u8; 32],
+    secret_born: Instant
+}
+
+impl CookieChecker {
+    pub fn new(public: &PublicKey) -> Self {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        CookieChecker { mac1_key: label_key(MAC1_LABEL, public), cookie_key: label_key(COOKIE_LABEL, public), secret, secret_born: Instant::now() }
+    }
+
+    fn cookie(&mut self, addr: &SocketAddr) -> [u8; MAC_LEN] {
+        if self.secret_born.elapsed() >= COOKIE_SECRET_LIFETIME {
+            OsRng.fill_bytes(&mut self.secret);
+            self.secret_born = Instant::now();
+        }
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec()
+        };
+        mac(&self.secret, &[&ip, &addr.port().to_be_bytes()])
+    }
+
+    /// `raw` is the initiation exactly as received.
+    pub fn check_mac1(&self, raw: &[u8]) -> bool {
+        let mac1_start = raw.len() - 2 * MAC_LEN;
+        verify(&self.mac1_key, &raw[..mac1_start], &raw[mac1_start..mac1_start + MAC_LEN])
+    }
+
+    pub fn check_mac2(&mut self, raw: &[u8], addr: &SocketAddr) -> bool {
+        let mac2_start = raw.len() - MAC_LEN;
+        let cookie = self.cookie(addr);
+        verify(&cookie, &raw[..mac2_start], &raw[mac2_start..])
+    }
+
+    pub fn create_reply(&mut self, raw: &[u8], addr: &SocketAddr) -> UDPCookieReply {
+        let mac1_start = raw.len() - 2 * MAC_LEN;
+        let cookie = self.cookie(addr);
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut nonce);
+        let encrypted_cookie = XChaCha20Poly1305::new(&self.cookie_key.into())
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: &cookie, aad: &raw[mac1_start..mac1_start + MAC_LEN] })
+            .expect("Cookie encryption cannot fail");
+        UDPCookieReply { nonce, encrypted_cookie }
+    }
+}
+
+impl Drop for CookieChecker {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+/// Client side: fills in macs and remembers the last cookie received.
+pub struct CookieGenerator {
+    mac1_key: [u8; 32],
+    cookie_key: [u8; 32],
+    cookie: Option<([u8; MAC_LEN], Instant)>,
+    last_mac1: Option<[u8; MAC_LEN]>
+}
+
+impl CookieGenerator {
+    pub fn new(server_public: &PublicKey) -> Self {
+        CookieGenerator { mac1_key: label_key(MAC1_LABEL, server_public), cookie_key: label_key(COOKIE_LABEL, server_public), cookie: None, last_mac1: None }
+    }
+
+    pub fn stamp(&mut self, handshake: &mut UDPVpnHandshake) {
+        let raw = handshake.serialize();
+        let body = &raw[..raw.len() - 2 * MAC_LEN];
+        handshake.mac1 = mac(&self.mac1_key, &[body]);
+        handshake.mac2 = match self.cookie {
+            Some((cookie, received)) if received.elapsed() < COOKIE_LIFETIME => mac(&cookie, &[body, &handshake.mac1]),
+            _ => [0u8; MAC_LEN]
+        };
+        self.last_mac1 = Some(handshake.mac1);
+    }
+
+    pub fn consume_reply(&mut self, reply: &UDPCookieReply) -> Result<(), NoiseError> {
+        let last_mac1 = self.last_mac1.ok_or(NoiseError::Decrypt)?;
+        let cookie = XChaCha20Poly1305::new(&self.cookie_key.into())
+            .decrypt(XNonce::from_slice(&reply.nonce), Payload { msg: &reply.encrypted_cookie, aad: &last_mac1 })
+            .map_err(|_| NoiseError::Decrypt)?;
+        let mut c = [0u8; MAC_LEN];
+        c.copy_from_slice(&cookie);
+        self.cookie = Some((c, Instant::now()));
+        Ok(())
+    }
+}
+
+/// Counts initiations per second. The server stays under load for the
+/// configured cooldown after the threshold was last exceeded.
+pub struct LoadDetector {
+    threshold: u32,
+    cooldown: Duration,
+    window: Instant,
+    count: u32,
+    until: Option<Instant>
+}
+
+impl LoadDetector {
+    pub fn new(cfg: &HandshakeConfig) -> Self {
+        LoadDetector { threshold: cfg.under_load_threshold, cooldown: Duration::from_secs(cfg.under_load_cooldown_sec), window: Instant::now(), count: 0, until: None }
+    }
+
+    /// Records one initiation and returns whether the server is under load.
+    pub fn record(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window) >= Duration::from_secs(1) {
+            self.window = now;
+            self.count = 0;
+        }
+        self.count = self.count.saturating_add(1);
+        if self.count > self.threshold {
+            self.until = Some(now + self.cooldown);
+        }
+        self.until.is_some_and(|until| now <= until)
+    }
+}
@@ -0,0 +1,17 @@
+#[allow(clippy::upper_case_acronyms)]
+pub mod obfs;
+pub mod server;
+pub mod client;
+pub mod udp;
+pub mod noise;
+pub mod session;
+pub mod cookie;
+pub mod ratelimit;
+pub mod routing;
+pub mod config;
+pub mod control;
+pub mod metrics;
+pub mod quota;
+pub mod batch;
+pub mod queue;
+//mod client_socks;
@@ -1,21 +1,17 @@
 
-use std::{fs, net::{Ipv4Addr}, str};
+use std::{fs, net::{IpAddr, Ipv4Addr}, process, str};
 use clap::{App, Arg, ArgMatches};
 use env_logger::Builder;
 use log::{error, LevelFilter};
-use crate::config::{ ServerConfiguration, ClientConfiguration, ObfsProtocol, ServerPeer };
-
-mod obfs;
-mod server;
-mod client;
-mod udp;
-mod config;
-//mod client_socks;
+use frida_vpn::{client, control, noise, server};
+use frida_vpn::config::{ ServerConfiguration, ClientConfiguration, ObfsProtocol, ServerPeer, check_peers, generate_preshared_key };
+use frida_vpn::control::{PeerInfo, Request, Response};
+use frida_vpn::routing::Cidr;
 
 fn generate_server_config(matches: &ArgMatches, config_path: &str) {
     let bind_address = matches.value_of("bind-address").expect("No bind address specified");
     let internal_address = matches.value_of("internal-address").expect("No internal address specified");
-    let broadcast_mode = matches.value_of("broadcast-mode").is_some();
+    let broadcast_mode = matches.is_present("broadcast-mode");
     let keepalive: u8 = matches.value_of("keepalive").unwrap().parse().expect("Keepalive argument should be a number");
     let obfs_type = match matches.value_of("obfs-type").expect("Obfs type should be specified") {
         "dns" => ObfsProtocol::FakeDNS,
@@ -27,11 +23,13 @@ fn generate_server_config(matches: &ArgMatches, config_path: &str) {
     let _ = fs::write(config_path, serde_yaml::to_string(&ServerConfiguration::default(bind_address, internal_address, broadcast_mode, keepalive, obfs_type)).unwrap());
 }
 
-fn generate_peer_config(matches: &ArgMatches, config_path: &str, cfg_raw: &String) {
+fn generate_peer_config(matches: &ArgMatches, config_path: &str, cfg_raw: &str) {
     let keepalive: u8 = matches.value_of("keepalive").unwrap().parse().expect("Keepalive argument should be a number");
     let grab_endpoint = matches.value_of("grab-endpoint").is_some();
-    let endpoint = matches.value_of("endpoint").or(Some("0.0.0.0:0")).unwrap();
+    let endpoint = matches.value_of("endpoint").unwrap_or("0.0.0.0:0");
     let peer_cfg = matches.value_of("peer-cfg").expect("No peer cfg path specified");
+    let preshared_key = matches.is_present("preshared-key").then(generate_preshared_key);
+    let allowed_ips = allowed_ips(matches);
 
     let mut config: ServerConfiguration = serde_yaml::from_str(cfg_raw).expect("Bad server config file structure");
 
@@ -42,27 +40,102 @@ fn generate_peer_config(matches: &ArgMatches, config_path: &str, cfg_raw: &Strin
                                     .map(|p| p.ip)
                                     .collect::<Vec<Ipv4Addr>>()
                                     .first()
-                                    .or(Some(&config.interface.internal_address.parse::<Ipv4Addr>().unwrap()))
-                                    .unwrap()
-                                    .clone();
+                                    .copied()
+                                    .unwrap_or_else(|| config.interface.internal_address.parse::<Ipv4Addr>().unwrap());
 
     internal_address = Ipv4Addr::new(internal_address.octets()[0], internal_address.octets()[1], internal_address.octets()[2], internal_address.octets()[3]+1);
 
     let cl_cfg = &ClientConfiguration::default(if grab_endpoint { &config.interface.bind_address } else { endpoint }, 
         keepalive, 
         &config.interface.public_key, 
-        &internal_address.to_string());
+        &internal_address.to_string(),
+        preshared_key.clone());
 
-    config.peers.push(ServerPeer { public_key: cl_cfg.client.public_key.clone(), ip: internal_address.clone() });
+    config.peers.push(ServerPeer { public_key: cl_cfg.client.public_key.clone(), ip: internal_address, preshared_key, allowed_ips, rate_limit: None, quota: None });
+    if let Err(e) = check_peers(&config.peers) {
+        error!("{}", e);
+        process::exit(1);
+    }
 
     let _ = fs::write(peer_cfg, serde_yaml::to_string(cl_cfg).unwrap());
 
     let _ = fs::write(config_path, serde_yaml::to_string(&config).unwrap());
 }
 
-async fn init_server(cfg_raw: &str, s_interface: Option<&str>) {
+fn allowed_ips(matches: &ArgMatches) -> Vec<Cidr> {
+    matches.value_of("allowed-ips").map_or(Vec::new(), |ips| ips.split(',')
+        .map(|ip| ip.parse::<Cidr>().expect("Allowed IPs should be comma-separated CIDRs"))
+        .collect())
+}
+
+async fn run_ctl(matches: &ArgMatches<'_>, cfg_raw: &str) {
+    let config: ServerConfiguration = serde_yaml::from_str(cfg_raw).expect("Bad server config file structure");
+    let args: Vec<&str> = matches.values_of("args").map_or(Vec::new(), |a| a.collect());
+    let request = match (matches.value_of("command"), &args[..]) {
+        (Some("sessions"), []) => Request::Sessions,
+        (Some("stats"), []) => Request::Stats,
+        (Some("add-peer"), [public_key, ip]) => {
+            noise::decode_key(public_key).expect("Public key should be a base64 X25519 key");
+            let ip = ip.parse::<Ipv4Addr>().expect("Peer address should be an IPv4 address");
+            Request::AddPeer { peer: ServerPeer { public_key: public_key.to_string(), ip, preshared_key: None, allowed_ips: allowed_ips(matches), rate_limit: None, quota: None } }
+        },
+        (Some("remove-peer"), [ip]) => Request::RemovePeer { ip: ip.parse().expect("Peer address should be an IPv4 address") },
+        (Some("kick"), [ip]) => Request::Kick { ip: ip.parse::<IpAddr>().expect("Peer address should be an IP address") },
+        _ => {
+            error!("Usage: ctl sessions | stats | add-peer PUBLIC_KEY IP [--allowed-ips CIDR,...] | remove-peer IP | kick IP");
+            process::exit(2);
+        }
+    };
+    match control::request(&config.control.socket, &request).await {
+        Ok(Response::Sessions(sessions)) => sessions.iter().for_each(|s| println!("{}", s)),
+        Ok(Response::Stats(stats)) => println!("{}", stats),
+        Ok(Response::Peers(peers)) => print_peers(&peers, false),
+        Ok(Response::Ok) => {},
+        Ok(Response::Error(e)) => {
+            error!("{}", e);
+            process::exit(1);
+        },
+        Err(e) => {
+            error!("Failed to reach the server at {}: {}", config.control.socket, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_peers(peers: &[PeerInfo], json: bool) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(peers).unwrap());
+    } else {
+        peers.iter().for_each(|p| println!("{}\n", p));
+    }
+}
+
+/// Works with either config, since a client answers `show` too.
+async fn show(matches: &ArgMatches<'_>, cfg_raw: &str) {
+    let socket = match serde_yaml::from_str::<ServerConfiguration>(cfg_raw) {
+        Ok(config) => config.control.socket,
+        Err(_) => serde_yaml::from_str::<ClientConfiguration>(cfg_raw).expect("Bad config file structure").control.socket
+    };
+    match control::request(&socket, &Request::Show).await {
+        Ok(Response::Peers(peers)) => print_peers(&peers, matches.is_present("json")),
+        Ok(Response::Error(e)) => {
+            error!("{}", e);
+            process::exit(1);
+        },
+        Ok(response) => {
+            error!("Unexpected response: {:?}", response);
+            process::exit(1);
+        },
+        Err(e) => {
+            error!("Failed to reach the VPN at {}: {}", socket, e);
+            process::exit(1);
+        }
+    }
+}
+
+async fn init_server(config_path: &str, cfg_raw: &str, s_interface: Option<&str>) {
     let config: ServerConfiguration = serde_yaml::from_str(cfg_raw).expect("Bad server config file structure");
-    server::server_mode(config, s_interface).await;
+    server::server_mode(config, config_path, s_interface).await;
 }
 
 async fn init_client(cfg_raw: &str, s_interface: Option<&str>) {
@@ -86,8 +159,16 @@ async fn main() {
         .arg(Arg::with_name("mode")
             .required(true)
             .index(1)
-            .possible_values(&["server", "client", "gen_cfg", "new_peer"])
+            .possible_values(&["server", "client", "gen_cfg", "new_peer", "ctl", "show"])
             .help("Runs the program in certain mode"))
+        .arg(Arg::with_name("command")
+            .index(2)
+            .possible_values(&["sessions", "stats", "add-peer", "remove-peer", "kick"])
+            .help("Request sent to a running server (ctl)"))
+        .arg(Arg::with_name("args")
+            .index(3)
+            .multiple(true)
+            .help("Arguments of the request (ctl)"))
         .arg(Arg::with_name("config")
             .long("config")
             .required(true)
@@ -122,6 +203,19 @@ async fn main() {
             .long("grab-endpoint")
             .help("If set to true, the endpoint address for peers will be grabbed from server config (config)")
             .takes_value(false))
+        .arg(Arg::with_name("preshared-key")
+            .long("preshared-key")
+            .help("If set, a pre-shared key is generated for the new peer (config)")
+            .takes_value(false))
+        .arg(Arg::with_name("allowed-ips")
+            .long("allowed-ips")
+            .value_name("CIDR,...")
+            .help("Further address ranges routed to the new peer, e.g. its LAN (config)")
+            .takes_value(true))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .help("Prints JSON instead of text (show)")
+            .takes_value(false))
         .arg(Arg::with_name("keepalive")
             .long("keepalive")
             .required(false)
@@ -160,9 +254,11 @@ async fn main() {
         let cfg_raw = &String::from_utf8(data.unwrap()).unwrap();
 
         match mode {
-            "server" => init_server(cfg_raw, matches.value_of("interface")).await,
+            "server" => init_server(config_path, cfg_raw, matches.value_of("interface")).await,
             "client" => init_client(cfg_raw, matches.value_of("interface")).await,
             "new_peer" => generate_peer_config(&matches, config_path, cfg_raw),
+            "ctl" => run_ctl(&matches, cfg_raw).await,
+            "show" => show(&matches, cfg_raw).await,
             _ => error!("There is config file already")
         }
     }
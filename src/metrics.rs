@@ -0,0 +1,128 @@
+use std::{fmt::{Display, Write}, future::Future};
+use log::{debug, error, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Prometheus text exposition format, built one metric family at a time.
+#[derive(Default)]
+pub struct Exposition {
+    out: String
+}
+
+impl Exposition {
+    /// Starts a family. Its samples must follow before the next one starts.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels.iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+                .collect::<Vec<String>>();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    /// A family with a single unlabelled sample.
+    pub fn single(&mut self, name: &str, kind: &str, help: &str, value: impl Display) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+/// Opens the HTTP listener Prometheus scrapes.
+pub async fn listen(address: &str) -> Option<TcpListener> {
+    match TcpListener::bind(address).await {
+        Ok(listener) => {
+            info!("Serving metrics at http://{}/metrics", address);
+            Some(listener)
+        },
+        Err(e) => { error!("Failed to open metrics listener {}: {}", address, e); None }
+    }
+}
+
+/// Answers `GET /metrics` with what `render` returns, one request per
+/// connection.
+pub async fn serve<F, R>(listener: TcpListener, render: F)
+where F: Fn() -> R + Clone + Send + 'static, R: Future<Output = String> + Send {
+    loop {
+        let Ok((stream, from)) = listener.accept().await else { continue; };
+        let render = render.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, render).await {
+                debug!("Failed to answer metrics request from {}: {}", from, e);
+            }
+        });
+    }
+}
+
+async fn respond<F, R>(mut stream: TcpStream, render: F) -> std::io::Result<()>
+where F: Fn() -> R, R: Future<Output = String> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN { return Ok(()); }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request_line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+    let (status, content_type, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", "text/plain; version=0.0.4", render().await),
+        (Some(b"GET"), _) => ("404 Not Found", "text/plain", String::from("Not found\n")),
+        _ => ("405 Method Not Allowed", "text/plain", String::from("Method not allowed\n"))
+    };
+    let head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, content_type, body.len());
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn scrape(address: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn label_escaping() {
+        let mut m = Exposition::default();
+        m.sample("frida_peer_bytes", &[("key", "a\\b\"c\nd"), ("ip", "10.66.66.2")], 7);
+        assert_eq!(m.finish(), "frida_peer_bytes{key=\"a\\\\b\\\"c\\nd\",ip=\"10.66.66.2\"} 7\n");
+    }
+
+    #[tokio::test]
+    async fn http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, || async {
+            let mut m = Exposition::default();
+            m.single("frida_peers", "gauge", "Configured peers", 3);
+            m.finish()
+        }));
+        let response = scrape(address, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP frida_peers Configured peers\n# TYPE frida_peers gauge\nfrida_peers 3\n"));
+        let response = scrape(address, "GET /other HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = scrape(address, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -0,0 +1,357 @@
+use std::{fmt, time::{SystemTime, UNIX_EPOCH}};
+use aes_gcm::{aead::{Aead, KeyInit, Payload}, Aes256Gcm, Nonce};
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+use crate::udp::{UDPVpnHandshake, UDPVpnHandshakeResponse, MAC_LEN};
+
+// Noise_IKpsk2: the client knows the server's static key up front, so the
+// handshake completes in a single round trip and authenticates both sides.
+//   <- s
+//   ...
+//   -> e, es, s, ss
+//   <- e, ee, se, psk
+// The pre-shared key is mixed in last, so recorded sessions stay secret even
+// if X25519 is broken later, e.g. by a quantum computer, as long as the PSK
+// is not disclosed. Peers without a PSK use an all-zero key. As in every psk
+// handshake, each `e` is mixed into the key as well as the hash.
+const PROTOCOL_NAME: &[u8] = b"Noise_IKpsk2_25519_AESGCM_SHA256";
+const PROLOGUE: &[u8] = b"frida_vpn";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NoiseError {
+    BadKey,
+    WeakKey,
+    Decrypt
+}
+
+impl fmt::Display for NoiseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoiseError::BadKey => write!(f, "malformed key"),
+            NoiseError::WeakKey => write!(f, "non-contributory public key"),
+            NoiseError::Decrypt => write!(f, "handshake decryption failed")
+        }
+    }
+}
+
+impl std::error::Error for NoiseError {}
+
+pub struct Keypair {
+    pub secret: StaticSecret,
+    pub public: PublicKey
+}
+
+impl Keypair {
+    pub fn from_base64(private_key: &str) -> Result<Self, NoiseError> {
+        let secret = StaticSecret::from(decode_key(private_key)?);
+        let public = PublicKey::from(&secret);
+        Ok(Keypair { secret, public })
+    }
+}
+
+pub fn decode_key(key: &str) -> Result<[u8; 32], NoiseError> {
+    BASE64_STANDARD.decode(key).ok()
+        .and_then(|k| <[u8; 32]>::try_from(k.as_slice()).ok())
+        .ok_or(NoiseError::BadKey)
+}
+
+/// Monotonic handshake timestamp (seconds and nanoseconds, big endian),
+/// compared bytewise by the server to reject replayed initiations.
+pub fn timestamp() -> [u8; 12] {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut t = [0u8; 12];
+    t[..8].copy_from_slice(&now.as_secs().to_be_bytes());
+    t[8..].copy_from_slice(&now.subsec_nanos().to_be_bytes());
+    t
+}
+
+/// Directional keys produced by a completed handshake.
+pub struct TransportKeys {
+    pub send: [u8; 32],
+    pub recv: [u8; 32]
+}
+
+impl Drop for TransportKeys {
+    fn drop(&mut self) {
+        self.send.zeroize();
+        self.recv.zeroize();
+    }
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    parts.iter().for_each(|p| mac.update(p));
+    mac.finalize().into_bytes().into()
+}
+
+fn hkdf(chaining_key: &[u8; 32], input: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut temp_key = hmac(chaining_key, &[input]);
+    let first = hmac(&temp_key, &[&[1]]);
+    let second = hmac(&temp_key, &[&first, &[2]]);
+    temp_key.zeroize();
+    (first, second)
+}
+
+fn hkdf3(chaining_key: &[u8; 32], input: &[u8]) -> ([u8; 32], [u8; 32], [u8; 32]) {
+    let mut temp_key = hmac(chaining_key, &[input]);
+    let first = hmac(&temp_key, &[&[1]]);
+    let second = hmac(&temp_key, &[&first, &[2]]);
+    let third = hmac(&temp_key, &[&second, &[3]]);
+    temp_key.zeroize();
+    (first, second, third)
+}
+
+fn dh(secret: &StaticSecret, public: &PublicKey) -> Result<[u8; 32], NoiseError> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(NoiseError::WeakKey);
+    }
+    Ok(*shared.as_bytes())
+}
+
+struct SymmetricState {
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+    key: Option<[u8; 32]>,
+    nonce: u64
+}
+
+impl SymmetricState {
+    fn new(responder_static: &PublicKey) -> Self {
+        let mut hash = [0u8; 32];
+        hash[..PROTOCOL_NAME.len()].copy_from_slice(PROTOCOL_NAME);
+        let mut state = SymmetricState { chaining_key: hash, hash, key: None, nonce: 0 };
+        state.mix_hash(PROLOGUE);
+        state.mix_hash(responder_static.as_bytes());
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = Sha256::new().chain_update(self.hash).chain_update(data).finalize().into();
+    }
+
+    fn mix_key(&mut self, mut input: [u8; 32]) {
+        let (chaining_key, key) = hkdf(&self.chaining_key, &input);
+        input.zeroize();
+        self.chaining_key = chaining_key;
+        self.key = Some(key);
+        self.nonce = 0;
+    }
+
+    fn mix_key_and_hash(&mut self, psk: &[u8; 32]) {
+        let (chaining_key, mut temp_hash, key) = hkdf3(&self.chaining_key, psk);
+        self.chaining_key = chaining_key;
+        self.mix_hash(&temp_hash);
+        temp_hash.zeroize();
+        self.key = Some(key);
+        self.nonce = 0;
+    }
+
+    fn cipher_nonce(&self) -> [u8; 12] {
+        let mut n = [0u8; 12];
+        n[4..].copy_from_slice(&self.nonce.to_be_bytes());
+        n
+    }
+
+    fn encrypt_and_hash(&mut self, plain: &[u8]) -> Vec<u8> {
+        let cipher = match &self.key {
+            Some(k) => {
+                let aes = Aes256Gcm::new(k.into());
+                aes.encrypt(Nonce::from_slice(&self.cipher_nonce()), Payload { msg: plain, aad: &self.hash })
+                    .expect("AES-GCM encryption of a handshake message cannot fail")
+            },
+            None => plain.to_vec()
+        };
+        self.nonce += 1;
+        self.mix_hash(&cipher);
+        cipher
+    }
+
+    fn decrypt_and_hash(&mut self, cipher: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let plain = match &self.key {
+            Some(k) => {
+                let aes = Aes256Gcm::new(k.into());
+                aes.decrypt(Nonce::from_slice(&self.cipher_nonce()), Payload { msg: cipher, aad: &self.hash })
+                    .map_err(|_| NoiseError::Decrypt)?
+            },
+            None => cipher.to_vec()
+        };
+        self.nonce += 1;
+        self.mix_hash(cipher);
+        Ok(plain)
+    }
+
+    fn split(&self) -> ([u8; 32], [u8; 32]) {
+        hkdf(&self.chaining_key, &[])
+    }
+}
+
+impl Drop for SymmetricState {
+    fn drop(&mut self) {
+        self.chaining_key.zeroize();
+        self.hash.zeroize();
+        self.key.zeroize();
+    }
+}
+
+/// Client side of the handshake, kept until the server's response arrives.
+pub struct Initiator {
+    state: SymmetricState,
+    ephemeral: StaticSecret,
+    local_static: StaticSecret
+}
+
+impl Initiator {
+    /// The caller fills in the session index and the macs.
+    pub fn new(local: &Keypair, remote_static: &PublicKey, payload: &[u8]) -> Result<(Self, UDPVpnHandshake), NoiseError> {
+        let mut state = SymmetricState::new(remote_static);
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+
+        state.mix_hash(ephemeral_public.as_bytes());
+        state.mix_key(ephemeral_public.to_bytes());
+        state.mix_key(dh(&ephemeral, remote_static)?);
+        let encrypted_static = state.encrypt_and_hash(local.public.as_bytes());
+        state.mix_key(dh(&local.secret, remote_static)?);
+        let encrypted_payload = state.encrypt_and_hash(payload);
+
+        let handshake = UDPVpnHandshake { sender: 0, ephemeral: ephemeral_public.to_bytes(), encrypted_static, encrypted_payload, mac1: [0; MAC_LEN], mac2: [0; MAC_LEN] };
+        Ok((Initiator { state, ephemeral, local_static: local.secret.clone() }, handshake))
+    }
+
+    /// Verifies the server's response. Success proves the server holds the
+    /// static key the client was configured with.
+    pub fn consume_response(mut self, response: &UDPVpnHandshakeResponse, psk: Option<&[u8; 32]>) -> Result<(TransportKeys, Vec<u8>), NoiseError> {
+        let remote_ephemeral = PublicKey::from(response.ephemeral);
+
+        self.state.mix_hash(remote_ephemeral.as_bytes());
+        self.state.mix_key(remote_ephemeral.to_bytes());
+        self.state.mix_key(dh(&self.ephemeral, &remote_ephemeral)?);
+        self.state.mix_key(dh(&self.local_static, &remote_ephemeral)?);
+        self.state.mix_key_and_hash(psk.unwrap_or(&[0u8; 32]));
+        let payload = self.state.decrypt_and_hash(&response.encrypted_payload)?;
+
+        let (send, recv) = self.state.split();
+        Ok((TransportKeys { send, recv }, payload))
+    }
+}
+
+/// Server side of the handshake, created from a verified initiation.
+pub struct Responder {
+    state: SymmetricState,
+    remote_static: PublicKey,
+    remote_ephemeral: PublicKey
+}
+
+impl Responder {
+    pub fn consume_initiation(local: &Keypair, handshake: &UDPVpnHandshake) -> Result<(Self, Vec<u8>), NoiseError> {
+        let mut state = SymmetricState::new(&local.public);
+        let remote_ephemeral = PublicKey::from(handshake.ephemeral);
+
+        state.mix_hash(remote_ephemeral.as_bytes());
+        state.mix_key(remote_ephemeral.to_bytes());
+        state.mix_key(dh(&local.secret, &remote_ephemeral)?);
+        let mut static_bytes = state.decrypt_and_hash(&handshake.encrypted_static)?;
+        let remote_static = <[u8; 32]>::try_from(static_bytes.as_slice())
+            .map(PublicKey::from)
+            .map_err(|_| NoiseError::BadKey)?;
+        static_bytes.zeroize();
+        state.mix_key(dh(&local.secret, &remote_static)?);
+        let payload = state.decrypt_and_hash(&handshake.encrypted_payload)?;
+
+        Ok((Responder { state, remote_static, remote_ephemeral }, payload))
+    }
+
+    pub fn remote_static(&self) -> &PublicKey {
+        &self.remote_static
+    }
+
+    /// The caller fills in the session indices.
+    pub fn respond(mut self, payload: &[u8], psk: Option<&[u8; 32]>) -> Result<(UDPVpnHandshakeResponse, TransportKeys), NoiseError> {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+
+        self.state.mix_hash(ephemeral_public.as_bytes());
+        self.state.mix_key(ephemeral_public.to_bytes());
+        self.state.mix_key(dh(&ephemeral, &self.remote_ephemeral)?);
+        self.state.mix_key(dh(&ephemeral, &self.remote_static)?);
+        self.state.mix_key_and_hash(psk.unwrap_or(&[0u8; 32]));
+        let encrypted_payload = self.state.encrypt_and_hash(payload);
+
+        let (recv, send) = self.state.split();
+        Ok((UDPVpnHandshakeResponse { sender: 0, receiver: 0, ephemeral: ephemeral_public.to_bytes(), encrypted_payload }, TransportKeys { send, recv }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair() -> Keypair {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    #[test]
+    fn round_trip() {
+        let (client, server) = (keypair(), keypair());
+        let (initiator, handshake) = Initiator::new(&client, &server.public, b"hello").unwrap();
+        let (responder, payload) = Responder::consume_initiation(&server, &handshake).unwrap();
+        assert_eq!(payload, b"hello");
+        assert_eq!(responder.remote_static().as_bytes(), client.public.as_bytes());
+        let (response, server_keys) = responder.respond(b"welcome", None).unwrap();
+        let (client_keys, payload) = initiator.consume_response(&response, None).unwrap();
+        assert_eq!(payload, b"welcome");
+        assert_eq!(client_keys.send, server_keys.recv);
+        assert_eq!(client_keys.recv, server_keys.send);
+        assert_ne!(client_keys.send, client_keys.recv);
+    }
+
+    #[test]
+    fn mismatched_psk() {
+        let (client, server) = (keypair(), keypair());
+        let (a, b) = ([1; 32], [2; 32]);
+        for (client_psk, server_psk) in [(Some(&a), None), (None, Some(&a)), (Some(&a), Some(&b))] {
+            let (initiator, handshake) = Initiator::new(&client, &server.public, b"").unwrap();
+            let (responder, _) = Responder::consume_initiation(&server, &handshake).unwrap();
+            let (response, _) = responder.respond(b"", server_psk).unwrap();
+            assert_eq!(initiator.consume_response(&response, client_psk).err(), Some(NoiseError::Decrypt));
+        }
+        let (initiator, handshake) = Initiator::new(&client, &server.public, b"").unwrap();
+        let (responder, _) = Responder::consume_initiation(&server, &handshake).unwrap();
+        let (response, _) = responder.respond(b"", Some(&a)).unwrap();
+        assert!(initiator.consume_response(&response, Some(&a)).is_ok());
+    }
+
+    #[test]
+    fn wrong_server_key() {
+        let (client, server) = (keypair(), keypair());
+        let (_, handshake) = Initiator::new(&client, &keypair().public, b"").unwrap();
+        assert_eq!(Responder::consume_initiation(&server, &handshake).err(), Some(NoiseError::Decrypt));
+    }
+
+    #[test]
+    fn tampered_initiation() {
+        let (client, server) = (keypair(), keypair());
+        let (_, mut handshake) = Initiator::new(&client, &server.public, b"hello").unwrap();
+        handshake.encrypted_static[0] ^= 1;
+        assert_eq!(Responder::consume_initiation(&server, &handshake).err(), Some(NoiseError::Decrypt));
+        let (_, mut handshake) = Initiator::new(&client, &server.public, b"hello").unwrap();
+        handshake.encrypted_payload[0] ^= 1;
+        assert_eq!(Responder::consume_initiation(&server, &handshake).err(), Some(NoiseError::Decrypt));
+    }
+
+    #[test]
+    fn low_order_ephemeral() {
+        let (client, server) = (keypair(), keypair());
+        let (_, mut handshake) = Initiator::new(&client, &server.public, b"").unwrap();
+        handshake.ephemeral = [0; 32];
+        assert_eq!(Responder::consume_initiation(&server, &handshake).err(), Some(NoiseError::WeakKey));
+    }
+}
@@ -18,7 +18,7 @@ impl Obfuscator for DNS {
         let mut tr_id = [0u8; 2];
         self.rng.fill_bytes(&mut tr_id);
         result.extend(tr_id);
-        let flags = [01u8, 00];
+        let flags = [1u8, 0];
         result.extend(flags);
         let mut questions = [0u8; 2];
         self.rng.fill_bytes(&mut questions);
@@ -0,0 +1,85 @@
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use crossbeam_channel::{Receiver, Sender};
+use log::warn;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::config::DropPolicy;
+
+/// Sending side of a bounded queue between tasks. When it is full, items
+/// wait or are dropped as the `DropPolicy` says, and drops are counted.
+pub struct Queue<T> {
+    sender: mpsc::Sender<T>,
+    name: &'static str,
+    policy: DropPolicy,
+    drops: Arc<AtomicU64>
+}
+
+impl<T> Clone for Queue<T> {
+    fn clone(&self) -> Self {
+        Queue { sender: self.sender.clone(), name: self.name, policy: self.policy, drops: self.drops.clone() }
+    }
+}
+
+impl<T> Queue<T> {
+    /// A queue of up to `depth` items, called `name` in logs.
+    pub fn new(name: &'static str, depth: usize, policy: DropPolicy) -> (Self, mpsc::Receiver<T>) {
+        let (sender, receiver) = mpsc::channel(depth.max(1));
+        (Queue { sender, name, policy, drops: Arc::default() }, receiver)
+    }
+
+    /// Queues `item` as the drop policy says.
+    pub async fn push(&self, item: T) {
+        match self.policy {
+            DropPolicy::TailDrop => self.offer(item),
+            DropPolicy::Block => { let _ = self.sender.send(item).await; }
+        }
+    }
+
+    /// Queues `item` if there is room, whatever the drop policy.
+    pub fn offer(&self, item: T) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(item) {
+            let total = self.drops.fetch_add(1, Ordering::Relaxed) + 1;
+            // Logging every drop would only add to the overload.
+            if total.is_power_of_two() {
+                warn!("The {} queue is full, {} packets dropped so far", self.name, total);
+            }
+        }
+    }
+
+    /// Items waiting in the queue.
+    pub fn depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Items dropped so far.
+    pub fn drops(&self) -> u64 {
+        self.drops.load(Ordering::Relaxed)
+    }
+}
+
+/// Spare packet buffers. Whoever is done with a buffer gives it back, so
+/// the receive path stops allocating once enough are going around.
+#[derive(Clone)]
+pub struct Pool {
+    free: Sender<Vec<u8>>,
+    spare: Receiver<Vec<u8>>
+}
+
+impl Pool {
+    /// A pool keeping up to `size` spare buffers.
+    pub fn new(size: usize) -> Self {
+        let (free, spare) = crossbeam_channel::bounded(size.max(1));
+        Pool { free, spare }
+    }
+
+    /// An empty buffer, a new one if none is spare.
+    pub fn take(&self) -> Vec<u8> {
+        self.spare.try_recv().unwrap_or_default()
+    }
+
+    /// Gives `buf` back, or frees it if the pool is full.
+    pub fn give(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let _ = self.free.try_send(buf);
+    }
+}
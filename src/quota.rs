@@ -0,0 +1,80 @@
+use std::{collections::HashMap, fs, io, path::Path};
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{Quota, QuotaPeriod};
+
+/// Bytes each peer with a quota used in its current period, by public key.
+/// This is what the state file holds.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Usage {
+    peers: HashMap<String, PeerUsage>,
+    #[serde(skip)]
+    dirty: bool
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PeerUsage {
+    period: String,
+    bytes: u64
+}
+
+fn period(quota: &Quota) -> String {
+    match quota.period {
+        QuotaPeriod::Monthly => Utc::now().format("%Y-%m").to_string(),
+        QuotaPeriod::Total => String::from("total")
+    }
+}
+
+impl Usage {
+    /// A missing file is a fresh start.
+    pub fn load(path: &str) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(raw) => serde_yaml::from_str(&raw).map_err(|e| e.to_string()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Usage::default()),
+            Err(e) => Err(e.to_string())
+        }
+    }
+
+    /// Writes the file if anything changed since the last save. A crash
+    /// mid-write leaves the previous file in place.
+    pub fn save(&mut self, path: &str) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(dir) = Path::new(path).parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, serde_yaml::to_string(self).map_err(io::Error::other)?)?;
+        fs::rename(&tmp, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Bytes used in the current period of `quota`.
+    pub fn used(&self, key: &str, quota: &Quota) -> u64 {
+        let current = period(quota);
+        self.peers.get(key).filter(|u| u.period == current).map_or(0, |u| u.bytes)
+    }
+
+    pub fn exceeded(&self, key: &str, quota: &Quota) -> bool {
+        self.used(key, quota) >= quota.bytes
+    }
+
+    /// Adds `bytes` to the current period, starting a new one if the last
+    /// has ended. Returns the bytes used in the period.
+    pub fn add(&mut self, key: &str, quota: &Quota, bytes: u64) -> u64 {
+        let current = period(quota);
+        let usage = self.peers.entry(key.to_string()).or_insert_with(|| PeerUsage { period: current.clone(), bytes: 0 });
+        if usage.period != current {
+            *usage = PeerUsage { period: current, bytes: 0 };
+            self.dirty = true;
+        }
+        if bytes > 0 {
+            usage.bytes += bytes;
+            self.dirty = true;
+        }
+        usage.bytes
+    }
+}
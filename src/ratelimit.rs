@@ -0,0 +1,31 @@
+use std::time::Instant;
+
+/// Token bucket refilled at `rate` tokens per second up to `burst`.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, burst: u64) -> Self {
+        TokenBucket { rate: rate as f64, burst: burst as f64, tokens: burst as f64, last: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Takes `n` tokens if that many are available.
+    pub fn take(&mut self, n: u64) -> bool {
+        self.refill();
+        if self.tokens < n as f64 {
+            return false;
+        }
+        self.tokens -= n as f64;
+        true
+    }
+}
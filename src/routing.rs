@@ -0,0 +1,242 @@
+use std::{collections::HashMap, fmt, net::IpAddr, str::FromStr};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CidrError {
+    BadAddress(String),
+    BadPrefix(String)
+}
+
+impl fmt::Display for CidrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CidrError::BadAddress(s) => write!(f, "bad address in {:?}", s),
+            CidrError::BadPrefix(s) => write!(f, "bad prefix length in {:?}", s)
+        }
+    }
+}
+
+impl std::error::Error for CidrError {}
+
+/// An address range such as `192.168.10.0/24`. A bare address is a single
+/// host. Host bits are cleared on parsing.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8
+}
+
+fn bits(addr: IpAddr) -> (u128, u8) {
+    match addr {
+        IpAddr::V4(a) => (u32::from(a) as u128, 32),
+        IpAddr::V6(a) => (u128::from(a), 128)
+    }
+}
+
+fn masked(value: u128, width: u8, prefix: u8) -> u128 {
+    if prefix == 0 { 0 } else { value & (u128::MAX >> (128 - width)) >> (width - prefix) << (width - prefix) }
+}
+
+impl Cidr {
+    pub fn host(addr: IpAddr) -> Self {
+        Cidr { addr, prefix: bits(addr).1 }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (value, width) = bits(addr);
+        self.addr.is_ipv4() == addr.is_ipv4() && masked(value, width, self.prefix) == bits(self.addr).0
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr: IpAddr = addr.trim().parse().map_err(|_| CidrError::BadAddress(s.to_string()))?;
+        let (value, width) = bits(addr);
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().ok().filter(|p| *p <= width).ok_or_else(|| CidrError::BadPrefix(s.to_string()))?,
+            None => width
+        };
+        let value = masked(value, width, prefix);
+        let addr = match addr {
+            IpAddr::V4(_) => IpAddr::V4((value as u32).into()),
+            IpAddr::V6(_) => IpAddr::V6(value.into())
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = CidrError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(c: Cidr) -> Self {
+        c.to_string()
+    }
+}
+
+/// Routes of one address family: a map of networks per prefix length, so
+/// a lookup costs one hash probe per prefix length in use.
+struct Family<T> {
+    width: u8,
+    networks: HashMap<u8, HashMap<u128, T>>,
+    prefixes: Vec<u8> // in use, longest first
+}
+
+impl<T> Family<T> {
+    fn new(width: u8) -> Self {
+        Family { width, networks: HashMap::new(), prefixes: Vec::new() }
+    }
+
+    fn insert(&mut self, network: u128, prefix: u8, value: T) {
+        if !self.prefixes.contains(&prefix) {
+            self.prefixes.push(prefix);
+            self.prefixes.sort_unstable_by(|a, b| b.cmp(a));
+        }
+        self.networks.entry(prefix).or_default().insert(network, value);
+    }
+
+    fn lookup(&self, value: u128) -> Option<&T> {
+        self.prefixes.iter().find_map(|&prefix| self.networks[&prefix].get(&masked(value, self.width, prefix)))
+    }
+}
+
+/// Longest-prefix-match table from destination address to peer.
+pub struct RoutingTable<T> {
+    v4: Family<T>,
+    v6: Family<T>
+}
+
+impl<T> Default for RoutingTable<T> {
+    fn default() -> Self {
+        RoutingTable { v4: Family::new(32), v6: Family::new(128) }
+    }
+}
+
+impl<T> RoutingTable<T> {
+    /// Replaces any route for exactly the same range.
+    pub fn insert(&mut self, cidr: Cidr, value: T) {
+        let (network, _) = bits(cidr.addr);
+        match cidr.addr {
+            IpAddr::V4(_) => self.v4.insert(network, cidr.prefix, value),
+            IpAddr::V6(_) => self.v6.insert(network, cidr.prefix, value)
+        }
+    }
+
+    pub fn lookup(&self, addr: IpAddr) -> Option<&T> {
+        let (value, _) = bits(addr);
+        match addr {
+            IpAddr::V4(_) => self.v4.lookup(value),
+            IpAddr::V6(_) => self.v6.lookup(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn masks() {
+        let v4 = u32::from(std::net::Ipv4Addr::new(192, 168, 10, 77)) as u128;
+        assert_eq!(masked(v4, 32, 0), 0);
+        assert_eq!(masked(v4, 32, 32), v4);
+        assert_eq!(masked(v4, 32, 24), v4 & !0xff);
+        assert_eq!(masked(u128::MAX, 128, 0), 0);
+        assert_eq!(masked(u128::MAX, 128, 128), u128::MAX);
+        assert_eq!(masked(u128::MAX, 128, 1), 1 << 127);
+        assert_eq!(masked(u128::MAX, 128, 64), u128::MAX << 64);
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(cidr("0.0.0.0/0"), Cidr { addr: ip("0.0.0.0"), prefix: 0 });
+        assert_eq!(cidr("10.1.2.3/0"), Cidr { addr: ip("0.0.0.0"), prefix: 0 });
+        assert_eq!(cidr("10.1.2.3/32"), Cidr::host(ip("10.1.2.3")));
+        assert_eq!(cidr("10.1.2.3"), Cidr::host(ip("10.1.2.3")));
+        assert_eq!(cidr("fd00::1/128"), Cidr::host(ip("fd00::1")));
+        assert_eq!(cidr("fd00::1"), Cidr { addr: ip("fd00::1"), prefix: 128 });
+        assert_eq!(cidr("::/0").prefix(), 0);
+        assert_eq!(cidr(" 10.0.0.1 / 8 ").to_string(), "10.0.0.0/8");
+    }
+
+    #[test]
+    fn host_bits_are_cleared() {
+        assert_eq!(cidr("192.168.10.77/24").addr(), ip("192.168.10.0"));
+        assert_eq!(cidr("192.168.10.77/23").addr(), ip("192.168.10.0"));
+        assert_eq!(cidr("192.168.10.77/31").addr(), ip("192.168.10.76"));
+        assert_eq!(cidr("fd00:1:2:3::1/64").addr(), ip("fd00:1:2:3::"));
+        assert_eq!(cidr("fd00:1:2:3::1/127").addr(), ip("fd00:1:2:3::"));
+    }
+
+    #[test]
+    fn rejects_bad_ranges() {
+        assert_eq!("10.0.0.0/33".parse::<Cidr>(), Err(CidrError::BadPrefix("10.0.0.0/33".into())));
+        assert_eq!("fd00::/129".parse::<Cidr>(), Err(CidrError::BadPrefix("fd00::/129".into())));
+        assert_eq!("10.0.0.0/".parse::<Cidr>(), Err(CidrError::BadPrefix("10.0.0.0/".into())));
+        assert_eq!("10.0.0.0/-1".parse::<Cidr>(), Err(CidrError::BadPrefix("10.0.0.0/-1".into())));
+        assert_eq!("10.0.0/8".parse::<Cidr>(), Err(CidrError::BadAddress("10.0.0/8".into())));
+        assert_eq!("".parse::<Cidr>(), Err(CidrError::BadAddress("".into())));
+    }
+
+    #[test]
+    fn contains() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("10.1.2.3/32").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.1.2.3/32").contains(ip("10.1.2.4")));
+        assert!(cidr("fd00::/64").contains(ip("fd00::ffff")));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut table = RoutingTable::default();
+        table.insert(cidr("0.0.0.0/0"), "default");
+        table.insert(cidr("10.0.0.0/8"), "wide");
+        table.insert(cidr("10.1.0.0/16"), "narrow");
+        table.insert(cidr("10.1.2.3/32"), "host");
+        table.insert(cidr("fd00::/16"), "v6 wide");
+        table.insert(cidr("fd00::1/128"), "v6 host");
+        assert_eq!(table.lookup(ip("10.1.2.3")), Some(&"host"));
+        assert_eq!(table.lookup(ip("10.1.2.4")), Some(&"narrow"));
+        assert_eq!(table.lookup(ip("10.2.0.1")), Some(&"wide"));
+        assert_eq!(table.lookup(ip("192.0.2.1")), Some(&"default"));
+        assert_eq!(table.lookup(ip("fd00::1")), Some(&"v6 host"));
+        assert_eq!(table.lookup(ip("fd00::2")), Some(&"v6 wide"));
+        assert_eq!(table.lookup(ip("fe80::1")), None);
+        table.insert(cidr("10.1.0.0/16"), "replaced");
+        assert_eq!(table.lookup(ip("10.1.9.9")), Some(&"replaced"));
+    }
+}
@@ -1,20 +1,31 @@
-use futures::{SinkExt, StreamExt};
 use tokio::sync::mpsc;
-use tokio::{net::UdpSocket, sync::Mutex, time};
-use x25519_dalek::{PublicKey, StaticSecret};
+use tokio::{net::UdpSocket, signal, sync::{Mutex, MutexGuard, RwLock}, time};
 use base64::prelude::*;
-use log::{error, info};
+use log::{debug, error, info, warn};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 use std::net::{ SocketAddr, Ipv4Addr, IpAddr };
 use std::collections::HashMap;
 use std::process::Command;
-use aes_gcm::{ aead::{Aead, AeadCore, KeyInit, OsRng},
-Aes256Gcm, Nonce };
+use std::fs;
 use network_interface::NetworkInterface;
 use network_interface::NetworkInterfaceConfig;
 
-use crate::config::{ ServerConfiguration, ServerPeer};
-use crate::udp::{UDPKeepAlive, UDPSerializable, UDPVpnHandshake, UDPVpnPacket};
+use crate::batch::{BatchSocket, SendBatch, BATCH};
+use crate::config::{ check_peers, diff_peers, CipherSuite, DropPolicy, ForwardingConfig, QueueConfig, QuotaPolicy, RateLimit, RekeyConfig, ServerConfiguration, ServerPeer};
+use crate::control::{self, PeerInfo, Request, Response, SessionInfo, Stats};
+use crate::cookie::{CookieChecker, LoadDetector};
+use crate::metrics::{self, Exposition};
+use crate::noise::{self, Keypair, Responder, TransportKeys};
+use crate::queue::{Pool, Queue};
+use crate::quota::Usage;
+use crate::ratelimit::TokenBucket;
+use crate::routing::{Cidr, RoutingTable};
+use crate::session::{DecryptError, Session, Sessions, Traffic};
+use crate::udp::{self, Message, UDPHandshakePayload, UDPHandshakeResponsePayload, UDPSerializable, UDPVpnPacket, PACKET_HEADER_LEN};
+
+const CONFIG_POLL_SEC: u64 = 2;
 
 fn configure_routes(s_interface: Option<&str>) {
     let interfaces = NetworkInterface::show().unwrap();
@@ -26,7 +37,7 @@ fn configure_routes(s_interface: Option<&str>) {
 
     info!("Main network interface: {:?}", net_inter.name);
 
-    let inter_name = if s_interface.is_some() { s_interface.unwrap() } else { &net_inter.name };
+    let inter_name = s_interface.unwrap_or(&net_inter.name);
 
     let mut ip_output = Command::new("iptables")
         .arg("-A")
@@ -81,7 +92,7 @@ fn configure_routes(s_interface: Option<&str>) {
     }
 }
 
-pub async fn server_mode(server_config: ServerConfiguration, s_interface: Option<&str>) {
+pub async fn server_mode(server_config: ServerConfiguration, config_path: &str, s_interface: Option<&str>) {
     info!("Starting server...");
     
     let mut config = tun2::Configuration::default();
@@ -90,159 +101,1167 @@ pub async fn server_mode(server_config: ServerConfiguration, s_interface: Option
         .tun_name("tun0")
         .up();
 
-    let dev = tun2::create_as_async(&config).unwrap();
-    let (mut dev_writer, mut dev_reader) = dev.into_framed().split();
+    let dev = Arc::new(tun2::create_as_async(&config).unwrap());
+    let dev_writer = dev.clone();
+
+    let keypair = Keypair::from_base64(&server_config.interface.private_key).expect("Bad server private key");
+    check_peers(&server_config.peers).unwrap_or_else(|e| panic!("Bad server config: {}", e));
 
     let sock = UdpSocket::bind(&server_config.interface.bind_address).await.unwrap();
-    let sock_rec = Arc::new(sock);
+    let sock_rec = Arc::new(BatchSocket::new(sock));
     let sock_hnd = sock_rec.clone();
-    let addresses = Arc::new(Mutex::new(HashMap::<IpAddr, UDPeer>::new()));
+    let workers = match server_config.data_plane.workers {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n
+    };
+    let shards = Shards::new(workers);
     let peers = Arc::new(Mutex::new(Vec::<ServerPeer>::new()));
+    let mut routing = RoutingTable::default();
+    server_config.peers.iter().for_each(|p| p.routes().for_each(|r| routing.insert(r, IpAddr::V4(p.ip))));
+    let routes = Arc::new(RwLock::new(routing)); // address range -> peer
+    let counters = Arc::new(Counters::default());
+    let state_file = server_config.quotas.state_file.clone();
+    let usage = Arc::new(Mutex::new(Usage::load(&state_file).unwrap_or_else(|e| panic!("Bad quota state file {}: {}", state_file, e))));
+    let state = PeerState { shards: shards.clone(), peers: peers.clone(), routes: routes.clone(), usage: usage.clone() };
 
-    let (send2tun, mut recv2tun) = mpsc::unbounded_channel::<Vec<u8>>(); // unbounded::<Vec<u8>>();
+    let queues = server_config.queues.clone();
+    let (send2tun, mut recv2tun) = Queue::<Vec<u8>>::new("tun", queues.tun, queues.policy);
+    // Enough for every queue to be full.
+    let pool = Pool::new(queues.tun + queues.worker * workers);
 
-    let (send2hnd, mut recv2hnd) = mpsc::unbounded_channel::<(Vec<u8>, SocketAddr)>(); // unbounded::<(Vec<u8>, SocketAddr)>();
+    let (send2hnd, mut recv2hnd) = Queue::<(Vec<u8>, SocketAddr)>::new("socket", queues.socket, queues.policy);
 
     #[cfg(target_os = "linux")]
     configure_routes(s_interface);
+    #[cfg(target_os = "linux")]
+    peer_routes("add", server_config.peers.iter().flat_map(|p| p.allowed_ips.iter()));
 
+    let counters_tw = counters.clone();
+    let pool_tw = pool.clone();
     let tun_writer_task = tokio::spawn(async move {
         loop {
             if let Some(bytes) = recv2tun.recv().await {
-                info!("Sent to tun!");
-                let _ = dev_writer.send(bytes).await;
+                debug!("Sent to tun!");
+                if let Err(e) = dev_writer.send(&bytes).await {
+                    counters_tw.tun_write_errors.fetch_add(1, Ordering::Relaxed);
+                    debug!("Failed to write to tun: {}", e);
+                }
+                pool_tw.give(bytes);
             }
         }
     });
 
-    let keepalive_sec = server_config.interface.keepalive.clone();
+    let keepalive_sec = server_config.interface.keepalive;
     let send2hnd_cl = send2hnd.clone();
-    let addrs_lcl = addresses.clone();
+    let shards_lcl = shards.clone();
+    let rekey = server_config.rekey.clone();
 
     let alive_task = tokio::spawn(async move {
-        let kp_sc = keepalive_sec.clone();
-        if kp_sc <= 0 { return; }
+        let kp_sc = keepalive_sec;
+        if kp_sc == 0 { return; }
         loop {
             time::sleep(time::Duration::from_secs(kp_sc.into())).await;
-            let mmp = addrs_lcl.lock().await;
-            mmp.values().for_each(|p| {
-                let _ = send2hnd_cl.send((UDPKeepAlive{}.serialize(), p.addr));
-            });
-            drop(mmp);
+            for shard in shards_lcl.iter() {
+                let mut s = shard.lock().await;
+                let keepalives = s.peers.iter_mut().filter_map(|(ip, p)| {
+                    if !p.sessions.is_responsive(time::Duration::from_secs(kp_sc.into())) {
+                        debug!("Peer {} did not answer the last keepalive", ip);
+                    }
+                    p.sessions.keepalive(&rekey).map(|keepalive| (keepalive, p.addr))
+                }).collect::<Vec<_>>();
+                drop(s);
+                for keepalive in keepalives {
+                    send2hnd_cl.push(keepalive).await;
+                }
+            }
         }
     });
 
     let sock_writer_task = tokio::spawn(async move {
         loop {
             if let Some((handshake, addr)) = recv2hnd.recv().await {
-                info!("I SENT THAT STUFF");
                 let _ = sock_hnd.send_to(&handshake, addr).await;
             }
         }
     });
 
-    let addrs_cl = addresses.clone();
-    let send2hnd_sr = send2hnd.clone();
+    let rekey = server_config.rekey.clone();
+    let timeouts = server_config.timeouts.clone();
+    let shards_exp = shards.clone();
+    let peers_exp = peers.clone();
+    let usage_exp = usage.clone();
+
+    let reaper_task = tokio::spawn(async move {
+        let idle = time::Duration::from_secs(timeouts.idle_sec);
+        let expiry = time::Duration::from_secs(timeouts.expiry_sec);
+        loop {
+            time::sleep(time::Duration::from_secs(1)).await;
+            let plp = peers_exp.lock().await;
+            for shard in shards_exp.iter() {
+                let mut s = shard.lock().await;
+                let mut usage = usage_exp.lock().await;
+                s.peers.retain(|ip, p| {
+                    p.sessions.expire(&rekey);
+                    let reason = if p.sessions.last_received().unwrap_or(p.handshake_at).elapsed() >= idle {
+                        "idle"
+                    } else if p.handshake_at.elapsed() >= expiry {
+                        "expired"
+                    } else {
+                        return true;
+                    };
+                    info!("Peer {} ({}) disconnected: {}, connected for {}s", ip, p.addr, reason, p.connected_at.elapsed().as_secs());
+                    p.account_configured(&plp, ip, &mut usage);
+                    false
+                });
+                drop(usage);
+                s.prune_indices();
+            }
+        }
+    });
+
+    let shards_qt = shards.clone();
+    let peers_qt = peers.clone();
+    let usage_qt = usage.clone();
+    let state_file_qt = state_file.clone();
+    let save_interval = time::Duration::from_secs(server_config.quotas.save_interval_sec);
+
+    let quota_task = tokio::spawn(async move {
+        let mut last_save = Instant::now();
+        loop {
+            time::sleep(time::Duration::from_secs(1)).await;
+            let plp = peers_qt.lock().await;
+            for shard in shards_qt.iter() {
+                let mut s = shard.lock().await;
+                let mut usage = usage_qt.lock().await;
+                let sessions = s.peers.len();
+                s.peers.retain(|ip, p| {
+                    let Some(server_peer) = plp.iter().find(|c| IpAddr::V4(c.ip) == *ip) else { return true; };
+                    let Some(quota) = &server_peer.quota else { return true; };
+                    let over = p.account(server_peer, &mut usage);
+                    match quota.policy {
+                        QuotaPolicy::Refuse if over => {
+                            info!("Peer {} ({}) disconnected: quota of {} bytes used up", ip, p.addr, quota.bytes);
+                            return false;
+                        },
+                        QuotaPolicy::Throttle if over != p.over_quota => {
+                            let limit = if over { quota.throttled() } else { server_peer.rate_limit.clone().unwrap_or_default() };
+                            p.ingress = limit.bucket(limit.ingress_bps);
+                            p.egress = limit.bucket(limit.egress_bps);
+                            p.over_quota = over;
+                            if over {
+                                info!("Peer {} used up its quota of {} bytes, throttled to {} bps", ip, quota.bytes, quota.throttle_bps);
+                            } else {
+                                info!("Peer {} is no longer throttled, its quota period started over", ip);
+                            }
+                        },
+                        _ => {}
+                    }
+                    true
+                });
+                drop(usage);
+                if s.peers.len() < sessions {
+                    s.prune_indices();
+                }
+            }
+            drop(plp);
+            if last_save.elapsed() >= save_interval {
+                last_save = Instant::now();
+                if let Err(e) = usage_qt.lock().await.save(&state_file_qt) {
+                    error!("Failed to save quota usage to {}: {}", state_file_qt, e);
+                }
+            }
+        }
+    });
+
+    let (job_senders, job_receivers): (Vec<_>, Vec<_>) = (0..workers)
+        .map(|_| Queue::<Job>::new("worker", queues.worker, queues.policy))
+        .unzip();
+    let data_plane = DataPlane {
+        shards: shards.clone(),
+        jobs: job_senders.into(),
+        routes: routes.clone(),
+        sock: sock_rec.clone(),
+        send2tun,
+        counters: counters.clone(),
+        rekey: server_config.rekey.clone(),
+        forwarding: server_config.forwarding.clone(),
+        broadcast_mode: server_config.interface.broadcast_mode,
+        broadcast_limit: Arc::new(std::sync::Mutex::new(TokenBucket::new(server_config.broadcast.packets_per_sec, server_config.broadcast.burst))),
+        subnet_broadcast: subnet_broadcast(&server_config.interface.internal_address),
+        pool
+    };
+    for (shard, jobs) in job_receivers.into_iter().enumerate() {
+        tokio::spawn(data_plane.clone().work(shard, jobs));
+    }
+    info!("Started {} data plane workers", workers);
+
+    let dp_tun = data_plane.clone();
+    let counters_tr = counters.clone();
     let tun_reader_task = tokio::spawn(async move {
-        while let Some(Ok(buf)) = dev_reader.next().await {
-            if buf.len() <= 19 { continue; }
-            
-            let ip = IpAddr::V4(Ipv4Addr::new(buf[16], buf[17], buf[18], buf[19]));
-            let mp = addrs_cl.lock().await;
-            if let Some(peer) = mp.get(&ip) {
-                
-                let aes = Aes256Gcm::new(&peer.shared_secret.into());
-                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-
-                let ciphered_data = aes.encrypt(&nonce, &buf[..]);
-
-                if let Ok(ciphered_d) = ciphered_data {
-                    let vpn_packet = UDPVpnPacket{ data: ciphered_d, nonce: nonce.to_vec()};
-                    let _ = send2hnd_sr.send((vpn_packet.serialize(), peer.addr));
-                } else {
-                    error!("Traffic encryption failed.");
+        let mut read_buf = vec![0; u16::MAX as usize];
+        loop {
+            let n = match dev.recv(&mut read_buf).await {
+                Ok(n) => n,
+                Err(e) => {
+                    counters_tr.tun_read_errors.fetch_add(1, Ordering::Relaxed);
+                    debug!("Failed to read from tun: {}", e);
+                    continue;
                 }
-            } else {
-                // TODO: check in config is broadcast mode enabled (if not, do not send this to everyone)
-                //mp.values().for_each(| peer | { sock_snd.send_to(&buf[..n], peer.addr); });
+            };
+            let mut buf = dp_tun.pool.take();
+            buf.extend_from_slice(&read_buf[..n]);
+            let Some((source, ip)) = packet_addresses(&buf) else { continue; };
+            let rt = dp_tun.routes.read().await;
+            let to_peer = rt.lookup(ip).copied();
+            let from_peer = rt.lookup(source).copied();
+            drop(rt);
+            // Only traffic between peers comes back from the kernel with a
+            // peer's source address.
+            if dp_tun.forwarding.isolate_peers && from_peer.is_some() {
+                debug!("Dropped packet from {} to {}: peers are isolated", source, ip);
+                continue;
+            }
+            if let Some(to_peer) = to_peer {
+                dp_tun.dispatch(to_peer, Job::Encrypt(to_peer, buf)).await;
+            } else if dp_tun.broadcast_mode && ip.is_ipv4() {
+                // Unknown destinations, including broadcasts sent by the
+                // server host itself. Packets the kernel routed back from a
+                // peer must not return to it.
+                if !dp_tun.broadcast_limit.lock().unwrap().take(1) {
+                    debug!("Broadcast rate limit exceeded, dropped packet to {}", ip);
+                    continue;
+                }
+                dp_tun.broadcast(buf, from_peer.unwrap_or(source));
             }
-            drop(mp);
         }
     });
     
-    let addrs_lp = addresses.clone();
+    let shards_lp = shards.clone();
     let peers_lp = peers.clone();
 
     let mut f_plp = peers_lp.lock().await;
     server_config.peers.iter().for_each(|c| f_plp.push(c.clone()));
     drop(f_plp);
 
+    // Peers are re-read on SIGHUP and whenever the config file changes.
+    // Other sections only take effect after a restart.
+    let (reload_tx, mut reload_rx) = mpsc::unbounded_channel::<&'static str>();
+
+    #[cfg(unix)]
+    {
+        let reload_hup = reload_tx.clone();
+        let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                let _ = reload_hup.send("SIGHUP");
+            }
+        });
+    }
+
+    // A restart would otherwise lose the usage since the last save.
+    let state_sd = state.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        state_sd.account_all().await;
+        if let Err(e) = state_sd.usage.lock().await.save(&state_file) {
+            error!("Failed to save quota usage to {}: {}", state_file, e);
+        }
+        info!("Shutting down");
+        std::process::exit(0);
+    });
+
+    let watched_path = config_path.to_string();
+    let watch_task = tokio::spawn(async move {
+        let modified = || fs::metadata(&watched_path).and_then(|m| m.modified()).ok();
+        let mut last = modified();
+        loop {
+            time::sleep(time::Duration::from_secs(CONFIG_POLL_SEC)).await;
+            let current = modified();
+            if current != last {
+                last = current;
+                let _ = reload_tx.send("config file change");
+            }
+        }
+    });
+
+    let reload_path = config_path.to_string();
+    let state_rl = state.clone();
+    let reload_task = tokio::spawn(async move {
+        while let Some(reason) = reload_rx.recv().await {
+            let result = match read_config(&reload_path) {
+                Ok(new_config) => state_rl.apply(new_config.peers, reason).await,
+                Err(e) => Err(e)
+            };
+            if let Err(e) = result {
+                error!("Failed to reload peers after {}: {}", reason, e);
+            }
+        }
+    });
+
+    let control = Control {
+        state: state.clone(),
+        counters: counters.clone(),
+        tun_queue: data_plane.send2tun.clone(),
+        sock_queue: send2hnd.clone(),
+        jobs: data_plane.jobs.clone(),
+        config_path: config_path.to_string(), started: Instant::now() };
+    #[cfg(unix)]
+    if let Some(listener) = control::listen(&server_config.control.socket) {
+        let control = control.clone();
+        tokio::spawn(control::serve(listener, move |request| {
+            let control = control.clone();
+            async move { control.handle(request).await }
+        }));
+    }
+    if let Some(metrics_config) = &server_config.metrics {
+        if let Some(listener) = metrics::listen(&metrics_config.listen).await {
+            tokio::spawn(metrics::serve(listener, move || {
+                let control = control.clone();
+                async move { control.metrics().await }
+            }));
+        }
+    }
+
     let send2hnd_ssr = send2hnd.clone();
+    let handshake_cfg = server_config.handshake.clone();
+    let cipher_suites = server_config.interface.cipher_suites.clone();
 
     let sock_reader_task = tokio::spawn(async move {
-        let mut buf = vec![0; 2048];
+        let mut batch = sock_rec.recv_batch(2048);
+        let mut cookies = CookieChecker::new(&keypair.public);
+        let mut load = LoadDetector::new(&handshake_cfg);
+        // Outlives reaped peers, so old initiations cannot be replayed later.
+        let mut timestamps = HashMap::<IpAddr, [u8; 12]>::new();
         loop {
-            if let Ok((len, addr)) = sock_rec.recv_from(&mut buf).await {
-                info!("There is packet!");
-                let mut mp = addrs_lp.lock().await;
-                let plp = peers_lp.lock().await;
-                match buf.first() {
-                    Some(h) => {
-                        match h {
-                            0 => {
-                                let handshake = UDPVpnHandshake::deserialize(&buf);
-                                info!("Got handshake from {:?}", handshake.request_ip);
-                                let skey = BASE64_STANDARD.encode(&handshake.public_key);
-                                if plp.iter().any(|c| c.ip == handshake.request_ip && c.public_key == skey) {
-                                    let internal_ip = IpAddr::V4(handshake.request_ip);
-                                    info!("Accepted client");
-                                    let mut k = [0u8; 32];
-                                    for (&x, p) in handshake.public_key.iter().zip(k.iter_mut()) {
-                                        *p = x;
-                                    }
-                                    let static_secret = BASE64_STANDARD.decode(&server_config.interface.private_key).unwrap();
-                                    let mut k1 = [0u8; 32];
-                                    for (&x, p) in static_secret.iter().zip(k1.iter_mut()) {
-                                        *p = x;
-                                    }
-                                    let shared_secret = StaticSecret::from(k1)
-                                        .diffie_hellman(&PublicKey::from(k));
-                                    mp.insert(internal_ip, UDPeer { addr, shared_secret: *shared_secret.as_bytes() });
-                                    
-                                    let handshake_response = UDPVpnHandshake{ public_key: BASE64_STANDARD.decode(&server_config.interface.public_key).unwrap(), request_ip: handshake.request_ip };
-    
-                                    let _ = send2hnd_ssr.send((handshake_response.serialize(), addr));
-                                } else {
-                                    info!("Bad handshake");
-                                    //plp.iter().for_each(|c| info!("ip: {:?}; pkey: {:?}", c.ip, c.public_key));
+            if sock_rec.recv(&mut batch).await.is_err() { continue; }
+            for (datagram, addr) in batch.datagrams() {
+                    debug!("There is packet!");
+                    let message = match udp::decode(datagram) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            let total = counters.malformed.fetch_add(1, Ordering::Relaxed) + 1;
+                            // Anyone can send these, so the log must not follow every one.
+                            if total.is_power_of_two() {
+                                warn!("Dropped malformed datagram from {}: {} ({} total)", addr, e, total);
+                            }
+                            continue;
+                        }
+                    };
+                    match message {
+                        Message::Handshake(handshake) => {
+                            let raw = &*datagram;
+                            if !cookies.check_mac1(raw) {
+                                let total = counters.reject(Reject::BadMac);
+                                debug!("Dropped handshake with bad mac1 from {} ({} total)", addr, total);
+                                continue;
+                            }
+                            if load.record() && !cookies.check_mac2(raw, &addr) {
+                                debug!("Under load, sent cookie to {}", addr);
+                                counters.reject(Reject::UnderLoad);
+                                send2hnd_ssr.push((cookies.create_reply(raw, &addr).serialize(), addr)).await;
+                                continue;
+                            }
+                            let (responder, payload) = match Responder::consume_initiation(&keypair, &handshake) {
+                                Ok(r) => r,
+                                Err(e) => { counters.reject(Reject::BadInitiation); info!("Bad handshake from {}: {}", addr, e); continue; }
+                            };
+                            let payload = match UDPHandshakePayload::deserialize(&payload) {
+                                Ok(p) => p,
+                                Err(e) => { counters.reject(Reject::BadPayload); info!("Bad handshake payload from {}: {}", addr, e); continue; }
+                            };
+                            info!("Got handshake from {:?}", payload.request_ip);
+                            let skey = BASE64_STANDARD.encode(responder.remote_static().as_bytes());
+                            let internal_ip = IpAddr::V4(payload.request_ip);
+                            let plp = peers_lp.lock().await;
+                            let Some(server_peer) = plp.iter().find(|c| c.ip == payload.request_ip && c.public_key == skey) else {
+                                counters.reject(Reject::UnknownPeer);
+                                info!("Bad handshake");
+                                continue;
+                            };
+                            let refused = match server_peer.quota.as_ref().filter(|q| q.policy == QuotaPolicy::Refuse) {
+                                Some(quota) => usage.lock().await.exceeded(&server_peer.public_key, quota),
+                                None => false
+                            };
+                            if refused {
+                                counters.reject(Reject::OverQuota);
+                                info!("Refused handshake from {}: quota used up", payload.request_ip);
+                                continue;
+                            }
+                            let psk = match server_peer.preshared_key.as_deref().map(noise::decode_key).transpose() {
+                                Ok(psk) => psk,
+                                Err(e) => { counters.reject(Reject::BadPresharedKey); error!("Bad preshared key of peer {}: {}", payload.request_ip, e); continue; }
+                            };
+                            if timestamps.get(&internal_ip).is_some_and(|t| *t >= payload.timestamp) {
+                                counters.reject(Reject::Replay);
+                                info!("Replayed handshake from {}", addr);
+                                continue;
+                            }
+                            let Some(suite) = cipher_suites.iter().find(|s| payload.cipher_suites.contains(&s.id())).copied() else {
+                                counters.reject(Reject::NoCipherSuite);
+                                info!("No common cipher suite with {}", payload.request_ip);
+                                continue;
+                            };
+                            let response_payload = UDPHandshakeResponsePayload { cipher_suite: suite.id() };
+                            match responder.respond(&response_payload.serialize(), psk.as_ref()) {
+                                Ok((mut response, keys)) => {
+                                    info!("Accepted client, cipher suite {:?}", suite);
+                                    counters.handshakes.fetch_add(1, Ordering::Relaxed);
+                                    // The index names the peer's shard, so its packets
+                                    // reach the right worker without a lookup.
+                                    let shard = shards_lp.of_peer(&internal_ip);
+                                    let mut s = shards_lp.lock(shard).await;
+                                    let index = shards_lp.free_index(shard, &s);
+                                    s.indices.insert(index, internal_ip);
+                                    response.sender = index;
+                                    response.receiver = handshake.sender;
+                                    let peer = s.peers.entry(internal_ip).or_insert_with(|| {
+                                        info!("Peer {} connected from {}", internal_ip, addr);
+                                        UDPeer::new(addr, &server_peer.rate_limit.clone().unwrap_or_default())
+                                    });
+                                    peer.addr = addr;
+                                    peer.allowed_ips = server_peer.routes().collect();
+                                    timestamps.insert(internal_ip, payload.timestamp);
+                                    peer.handshake_at = Instant::now();
+                                    peer.sessions.set_next(Session::new(keys, suite, index, handshake.sender));
+                                    send2hnd_ssr.push((response.serialize(), addr)).await;
+                                },
+                                Err(e) => {
+                                    counters.reject(Reject::BadInitiation);
+                                    info!("Bad handshake from {}: {}", addr, e)
                                 }
-                            }, // handshake
-                            1 => {
-                                let packet = UDPVpnPacket::deserialize(&(buf[..len].to_vec()));
-                                mp.values().filter(| p | p.addr == addr).for_each(|p| {
-                                    let aes = Aes256Gcm::new(&p.shared_secret.into());
-                                    let nonce = Nonce::clone_from_slice(&packet.nonce[..]);
-                                    match aes.decrypt(&nonce, &packet.data[..]) {
-                                        Ok(decrypted) => { let _ = send2tun.send(decrypted); },
-                                        Err(error) => error!("Decryption error! {:?}", error)
-                                    }
-                                });
-                            }, // payload
-                            _ => error!("Unexpected header value.")
+                            }
+                        },
+                        Message::HandshakeResponse(_) | Message::CookieReply(_) => warn!("Unexpected handshake response from {}", addr),
+                        Message::Packet(packet) => {
+                            let index = packet.receiver;
+                            data_plane.receive(index, datagram, addr).await;
                         }
-                    },
-                    None => error!("There is no header")
-                }
-                drop(plp);
-                drop(mp);
+                    }
             }
         }
     });
     
-    tokio::join!(tun_reader_task, sock_reader_task, sock_writer_task, tun_writer_task, alive_task);
+    let _ = tokio::join!(tun_reader_task, sock_reader_task, sock_writer_task, tun_writer_task, alive_task, reaper_task, quota_task, watch_task, reload_task);
+}
+
+fn read_config(path: &str) -> Result<ServerConfiguration, String> {
+    let raw = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    serde_yaml::from_str(&raw).map_err(|e| e.to_string())
+}
+
+/// Handles on the peer tables, for the tasks that change them wholesale.
+#[derive(Clone)]
+struct PeerState {
+    shards: Shards,
+    peers: Arc<Mutex<Vec<ServerPeer>>>,
+    routes: Arc<RwLock<RoutingTable<IpAddr>>>,
+    usage: Arc<Mutex<Usage>>
+}
+
+// Locks are taken in this order: configured peers, a shard, quota usage.
+
+impl PeerState {
+    /// Replaces the configured peers. Removed peers lose their session and
+    /// routes, added ones may handshake right away and unchanged ones keep
+    /// their session. Invalid peers leave everything as it was.
+    async fn apply(&self, new_peers: Vec<ServerPeer>, reason: &str) -> Result<(), String> {
+        check_peers(&new_peers)?;
+        let mut plp = self.peers.lock().await;
+        let (removed, added) = diff_peers(&plp, &new_peers);
+        if removed.is_empty() && added.is_empty() { return Ok(()); }
+        for peer in &removed {
+            let ip = IpAddr::V4(peer.ip);
+            let mut s = self.shards.lock_peer(&ip).await;
+            if let Some(mut p) = s.peers.remove(&ip) {
+                info!("Peer {} ({}) disconnected: removed from config", peer.ip, p.addr);
+                p.account(peer, &mut *self.usage.lock().await);
+                s.prune_indices();
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            peer_routes("del", removed.iter().flat_map(|p| p.allowed_ips.iter()));
+            peer_routes("add", added.iter().flat_map(|p| p.allowed_ips.iter()));
+        }
+        info!("Reloaded peers after {}: {} added, {} removed, {} total", reason, added.len(), removed.len(), new_peers.len());
+        let mut rt = self.routes.write().await;
+        *rt = RoutingTable::default();
+        new_peers.iter().for_each(|p| p.routes().for_each(|r| rt.insert(r, IpAddr::V4(p.ip))));
+        *plp = new_peers;
+        Ok(())
+    }
+
+    /// Adds the traffic of every session to the quotas.
+    async fn account_all(&self) {
+        let plp = self.peers.lock().await;
+        for shard in self.shards.iter() {
+            let mut s = shard.lock().await;
+            let mut usage = self.usage.lock().await;
+            s.peers.iter_mut().for_each(|(ip, p)| p.account_configured(&plp, ip, &mut usage));
+        }
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = signal::ctrl_c() => {},
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = signal::ctrl_c().await;
+}
+
+/// Connected peers and their session indices. Each worker owns one shard.
+#[derive(Default)]
+struct Shard {
+    peers: HashMap<IpAddr, UDPeer>,
+    indices: HashMap<u32, IpAddr> // session index -> peer
+}
+
+impl Shard {
+    /// Forgets the indices of sessions that are gone.
+    fn prune_indices(&mut self) {
+        let peers = &self.peers;
+        self.indices.retain(|index, ip| peers.get(ip).is_some_and(|p| p.sessions.has_index(*index)));
+    }
+}
+
+/// Peers are spread over the shards by tunnel address, and a session index
+/// always falls in its peer's shard.
+#[derive(Clone)]
+struct Shards(Arc<[Mutex<Shard>]>);
+
+impl Shards {
+    fn new(count: usize) -> Self {
+        Shards((0..count).map(|_| Mutex::new(Shard::default())).collect())
+    }
+
+    fn of_peer(&self, ip: &IpAddr) -> usize {
+        match ip {
+            IpAddr::V4(ip) => u32::from(*ip) as usize % self.0.len(),
+            IpAddr::V6(ip) => (u128::from(*ip) % self.0.len() as u128) as usize
+        }
+    }
+
+    fn of_index(&self, index: u32) -> usize {
+        index as usize % self.0.len()
+    }
+
+    /// A random session index of `shard` that is not taken yet.
+    fn free_index(&self, shard: usize, s: &Shard) -> u32 {
+        loop {
+            let index = rand::random::<u32>();
+            if self.of_index(index) == shard && !s.indices.contains_key(&index) { return index; }
+        }
+    }
+
+    async fn lock(&self, shard: usize) -> MutexGuard<'_, Shard> {
+        self.0[shard].lock().await
+    }
+
+    async fn lock_peer(&self, ip: &IpAddr) -> MutexGuard<'_, Shard> {
+        self.lock(self.of_peer(ip)).await
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Mutex<Shard>> {
+        self.0.iter()
+    }
+}
+
+/// Work for the worker owning a shard. A peer's jobs are queued to one
+/// worker only, so its packets keep their order.
+enum Job {
+    Decrypt(Vec<u8>, SocketAddr), // a data packet, copied out of the receive buffers
+    Encrypt(IpAddr, Vec<u8>),
+    Broadcast(Arc<Vec<u8>>, IpAddr)
+}
+
+/// Everything the workers share. A data packet waits for its peer's shard
+/// only; the routing table changes on reloads alone.
+#[derive(Clone)]
+struct DataPlane {
+    shards: Shards,
+    jobs: Arc<[Queue<Job>]>,
+    routes: Arc<RwLock<RoutingTable<IpAddr>>>,
+    sock: Arc<BatchSocket>,
+    send2tun: Queue<Vec<u8>>,
+    counters: Arc<Counters>,
+    rekey: RekeyConfig,
+    forwarding: ForwardingConfig,
+    broadcast_mode: bool,
+    broadcast_limit: Arc<std::sync::Mutex<TokenBucket>>,
+    subnet_broadcast: Ipv4Addr,
+    pool: Pool // of the packets in jobs and on their way to the tun device
+}
+
+impl DataPlane {
+    /// Queues a data packet from `addr` for session `index`. It is
+    /// decrypted by the worker serving the peer, in order.
+    async fn receive(&self, index: u32, datagram: &[u8], addr: SocketAddr) {
+        let mut buf = self.pool.take();
+        buf.extend_from_slice(datagram);
+        self.jobs[self.shards.of_index(index)].push(Job::Decrypt(buf, addr)).await;
+    }
+
+    /// Queues `job` for the worker serving `peer`.
+    async fn dispatch(&self, peer: IpAddr, job: Job) {
+        self.jobs[self.shards.of_peer(&peer)].push(job).await;
+    }
+
+    /// Like `dispatch`, but never waits. Workers forward to each other
+    /// with it, so two of them cannot end up waiting on each other.
+    fn forward(&self, peer: IpAddr, job: Job) {
+        self.jobs[self.shards.of_peer(&peer)].offer(job);
+    }
+
+    /// Has every worker send `packet` to its peers, except `sender`. Never
+    /// waits, as workers broadcast too.
+    fn broadcast(&self, packet: Vec<u8>, sender: IpAddr) {
+        let packet = Arc::new(packet);
+        self.jobs.iter().for_each(|jobs| jobs.offer(Job::Broadcast(packet.clone(), sender)));
+    }
+
+    /// Runs the jobs of `shard`, as many at a time as are queued up to a
+    /// batch. Their datagrams and tun packets go out together once the
+    /// shard is unlocked, so nothing is awaited while it is locked.
+    async fn work(self, shard: usize, mut jobs: mpsc::Receiver<Job>) {
+        let mut pending = Vec::with_capacity(BATCH);
+        let mut out = SendBatch::default();
+        let mut tun = Vec::with_capacity(BATCH);
+        while jobs.recv_many(&mut pending, BATCH).await > 0 {
+            // Taken first: reloads write it with no shard locked.
+            let routes = self.routes.read().await;
+            let mut s = self.shards.lock(shard).await;
+            for job in pending.drain(..) {
+                match job {
+                    Job::Decrypt(datagram, addr) => {
+                        if let Some(spare) = self.decrypt(&mut s, &routes, datagram, addr, &mut out, &mut tun) {
+                            self.pool.give(spare);
+                        }
+                    },
+                    Job::Encrypt(ip, packet) => {
+                        self.encrypt(&mut s, ip, &packet, &mut out);
+                        self.pool.give(packet);
+                    },
+                    Job::Broadcast(packet, sender) => broadcast(&mut s.peers, &packet, sender, &self.rekey, &mut out)
+                }
+            }
+            drop(s);
+            drop(routes);
+            let _ = self.sock.send(&out).await;
+            out.clear();
+            for packet in tun.drain(..) {
+                self.send2tun.push(packet).await;
+            }
+        }
+    }
+
+    fn encrypt(&self, shard: &mut Shard, ip: IpAddr, packet: &[u8], out: &mut SendBatch) {
+        let Some(peer) = shard.peers.get_mut(&ip) else { return; };
+        if !peer.sessions.is_established() { return; }
+        if !peer.admit_egress(packet.len()) {
+            debug!("Dropped packet to peer {}: rate limit exceeded", ip);
+            return;
+        }
+        if !out.push_with(peer.addr, |buf| peer.sessions.encrypt(packet, buf, &self.rekey)) {
+            error!("Traffic encryption failed.");
+        }
+    }
+
+    /// Hands the datagram back if nothing kept the packet in it.
+    fn decrypt(&self, shard: &mut Shard, routes: &RoutingTable<IpAddr>, mut datagram: Vec<u8>, addr: SocketAddr, out: &mut SendBatch, tun: &mut Vec<Vec<u8>>) -> Option<Vec<u8>> {
+        // The socket reader decoded it already.
+        let Ok(mut packet) = UDPVpnPacket::deserialize(&mut datagram) else { return Some(datagram); };
+        let ip = shard.indices.get(&packet.receiver).copied();
+        let Some((ip, p)) = ip.and_then(|ip| shard.peers.get_mut(&ip).map(|p| (ip, p))) else {
+            self.counters.decrypt_error(&DecryptError::NoSession, addr);
+            return Some(datagram);
+        };
+        let len = match p.sessions.decrypt(&mut packet, &self.rekey) {
+            Ok(decrypted) => decrypted.len(),
+            Err(e) => {
+                self.counters.decrypt_error(&e, addr);
+                return Some(datagram);
+            }
+        };
+        // Only authenticated packets may move a peer, so a
+        // spoofed source address cannot hijack its traffic.
+        if p.addr != addr {
+            info!("Peer {} roamed from {} to {}", ip, p.addr, addr);
+            p.addr = addr;
+        }
+        out.push_with(addr, |buf| p.sessions.keepalive_reply(buf, &self.rekey));
+        if len == 0 { return Some(datagram); }
+        // The datagram turns into the IP packet it was decrypted to.
+        datagram.truncate(PACKET_HEADER_LEN + len);
+        datagram.drain(..PACKET_HEADER_LEN);
+        let decrypted = datagram;
+        let Some((source, destination)) = packet_addresses(&decrypted) else {
+            debug!("Dropped non-IP packet from peer {}", ip);
+            return Some(decrypted);
+        };
+        // Link-local IPv6 traffic, e.g. router solicitations the
+        // client's kernel sends on its own, never leaves the link.
+        if matches!(source, IpAddr::V6(s) if s.is_unicast_link_local()) {
+            debug!("Dropped link-local packet from peer {}", ip);
+            return Some(decrypted);
+        }
+        // An authenticated peer may still forge the source of
+        // what it tunnels, e.g. another peer's address.
+        if !p.allowed_ips.iter().any(|range| range.contains(source)) {
+            p.spoofed += 1;
+            warn!("Dropped packet from peer {} with spoofed source {} ({} total)", ip, source, p.spoofed);
+            return Some(decrypted);
+        }
+        if !p.admit_ingress(decrypted.len()) {
+            debug!("Dropped packet from peer {}: rate limit exceeded", ip);
+            return Some(decrypted);
+        }
+        let to_peer = routes.lookup(destination).copied().filter(|d| *d != ip);
+        // The kernel delivers broadcasts from peers locally and
+        // never routes them back into the tunnel, so they are
+        // fanned out here before going to the tun device.
+        if self.broadcast_mode && !self.forwarding.isolate_peers && is_broadcast(&decrypted, self.subnet_broadcast) {
+            if self.broadcast_limit.lock().unwrap().take(1) {
+                self.broadcast(decrypted.clone(), ip);
+            } else {
+                debug!("Broadcast rate limit exceeded, dropped broadcast from {}", ip);
+            }
+        }
+        if let Some(to_peer) = to_peer {
+            if self.forwarding.isolate_peers {
+                debug!("Dropped packet from peer {} to {}: peers are isolated", ip, to_peer);
+                return Some(decrypted);
+            }
+            // Saves the round trip through the tun device and kernel routing.
+            // The target may live in another shard, so its worker encrypts.
+            if self.forwarding.in_process {
+                self.forward(to_peer, Job::Encrypt(to_peer, decrypted));
+                return None;
+            }
+        }
+        tun.push(decrypted);
+        None
+    }
+}
+
+/// The data plane from the socket to the tun device, without the device
+/// and without handshakes, for benchmarks. Sessions are set up with
+/// `add_peer`; what the workers decrypt comes out of `delivered`.
+#[doc(hidden)]
+pub struct Pipeline {
+    data_plane: DataPlane,
+    delivered: mpsc::Receiver<Vec<u8>>
+}
+
+impl Pipeline {
+    /// Starts `workers` workers and a reader for `sock` on the current runtime.
+    pub fn start(sock: UdpSocket, workers: usize) -> Self {
+        let counters = Arc::new(Counters::default());
+        let queues = QueueConfig { policy: DropPolicy::Block, ..QueueConfig::default() };
+        let (send2tun, delivered) = Queue::new("tun", queues.tun, queues.policy);
+        let (job_senders, job_receivers): (Vec<_>, Vec<_>) = (0..workers)
+            .map(|_| Queue::<Job>::new("worker", queues.worker, queues.policy))
+            .unzip();
+        let data_plane = DataPlane {
+            shards: Shards::new(workers),
+            jobs: job_senders.into(),
+            routes: Arc::default(),
+            sock: Arc::new(BatchSocket::new(sock)),
+            send2tun,
+            counters,
+            rekey: RekeyConfig { max_bytes: u64::MAX, max_packets: u64::MAX, ..RekeyConfig::default() },
+            forwarding: ForwardingConfig::default(),
+            broadcast_mode: false,
+            broadcast_limit: Arc::new(std::sync::Mutex::new(TokenBucket::new(0, 0))),
+            subnet_broadcast: Ipv4Addr::BROADCAST,
+            pool: Pool::new(queues.tun + queues.worker * workers)
+        };
+        for (shard, jobs) in job_receivers.into_iter().enumerate() {
+            tokio::spawn(data_plane.clone().work(shard, jobs));
+        }
+        let dp = data_plane.clone();
+        tokio::spawn(async move {
+            let mut batch = dp.sock.recv_batch(2048);
+            loop {
+                if dp.sock.recv(&mut batch).await.is_err() { continue; }
+                for (datagram, addr) in batch.datagrams() {
+                    if let Ok(Message::Packet(packet)) = udp::decode(datagram) {
+                        let index = packet.receiver;
+                        dp.receive(index, datagram, addr).await;
+                    }
+                }
+            }
+        });
+        Pipeline { data_plane, delivered }
+    }
+
+    /// Connects peer `ip` at `addr` with the server's keys of a finished
+    /// handshake. Returns the index the peer has to send its packets to.
+    pub async fn add_peer(&self, ip: IpAddr, addr: SocketAddr, keys: TransportKeys, suite: CipherSuite, remote_index: u32) -> u32 {
+        let shards = &self.data_plane.shards;
+        let shard = shards.of_peer(&ip);
+        let mut s = shards.lock(shard).await;
+        let index = shards.free_index(shard, &s);
+        s.indices.insert(index, ip);
+        let mut peer = UDPeer::new(addr, &RateLimit::default());
+        peer.allowed_ips = vec![Cidr::host(ip)];
+        peer.sessions.rotate(Session::new(keys, suite, index, remote_index));
+        s.peers.insert(ip, peer);
+        index
+    }
+
+    /// The next packet bound for the tun device.
+    pub async fn delivered(&mut self) -> Option<Vec<u8>> {
+        self.delivered.recv().await
+    }
+}
+
+/// Why an initiation got no handshake response.
+#[derive(Clone, Copy)]
+enum Reject {
+    BadMac,
+    UnderLoad, // answered with a cookie instead
+    BadInitiation,
+    BadPayload,
+    UnknownPeer,
+    BadPresharedKey,
+    Replay,
+    NoCipherSuite,
+    OverQuota
+}
+
+const REJECT_REASONS: [&str; 9] = ["bad_mac1", "under_load", "bad_initiation", "bad_payload", "unknown_peer", "bad_preshared_key", "replay", "no_cipher_suite", "over_quota"];
+const DECRYPT_ERRORS: [&str; 3] = ["no_session", "replay", "auth"];
+
+/// Server-wide counters reported by `frida_vpn ctl stats` and the metrics
+/// exporter.
+#[derive(Default)]
+struct Counters {
+    handshakes: AtomicU64,
+    rejects: [AtomicU64; REJECT_REASONS.len()],
+    decrypt_errors: [AtomicU64; DECRYPT_ERRORS.len()],
+    malformed: AtomicU64,
+    tun_read_errors: AtomicU64,
+    tun_write_errors: AtomicU64
+}
+
+impl Counters {
+    /// Returns the total for `reason` so far.
+    fn reject(&self, reason: Reject) -> u64 {
+        self.rejects[reason as usize].fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Counts a packet from `addr` that failed to decrypt. Such packets
+    /// are not authenticated, so only some of them are logged.
+    fn decrypt_error(&self, e: &DecryptError, addr: SocketAddr) {
+        let i = match e {
+            DecryptError::NoSession => 0,
+            DecryptError::Replay => 1,
+            DecryptError::Auth => 2
+        };
+        let total = self.decrypt_errors[i].fetch_add(1, Ordering::Relaxed) + 1;
+        if total.is_power_of_two() {
+            warn!("Dropped packet from {}: {} ({} total)", addr, e, total);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Control {
+    state: PeerState,
+    counters: Arc<Counters>,
+    // Held to read the depths of the queues
+    tun_queue: Queue<Vec<u8>>,
+    sock_queue: Queue<(Vec<u8>, SocketAddr)>,
+    jobs: Arc<[Queue<Job>]>,
+    config_path: String,
+    started: Instant
+}
+
+impl Control {
+    async fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::Sessions => {
+                let mut sessions = Vec::new();
+                for shard in self.state.shards.iter() {
+                    sessions.extend(shard.lock().await.peers.iter().map(|(ip, p)| SessionInfo {
+                        ip: *ip,
+                        endpoint: p.addr,
+                        last_handshake_sec: p.handshake_at.elapsed().as_secs(),
+                        connected_sec: p.connected_at.elapsed().as_secs(),
+                        rtt_ms: p.sessions.rtt().map(|rtt| rtt.as_millis() as u64)
+                    }));
+                }
+                Response::Sessions(sessions)
+            },
+            Request::Stats => {
+                let peers = self.state.peers.lock().await.len();
+                let (mut sessions, mut spoofed) = (0, 0);
+                for shard in self.state.shards.iter() {
+                    let s = shard.lock().await;
+                    sessions += s.peers.len();
+                    spoofed += s.peers.values().map(|p| p.spoofed).sum::<u64>();
+                }
+                Response::Stats(Stats {
+                    uptime_sec: self.started.elapsed().as_secs(),
+                    peers,
+                    sessions,
+                    handshakes: self.counters.handshakes.load(Ordering::Relaxed),
+                    cookie_replies: self.counters.rejects[Reject::UnderLoad as usize].load(Ordering::Relaxed),
+                    malformed: self.counters.malformed.load(Ordering::Relaxed),
+                    bad_mac: self.counters.rejects[Reject::BadMac as usize].load(Ordering::Relaxed),
+                    spoofed,
+                    queue_drops: self.queue_drops().iter().map(|(_, drops)| drops).sum()
+                })
+            },
+            Request::Show => {
+                let plp = self.state.peers.lock().await;
+                let mut peers = Vec::with_capacity(plp.len());
+                for peer in plp.iter() {
+                    let ip = IpAddr::V4(peer.ip);
+                    let s = self.state.shards.lock_peer(&ip).await;
+                    let session = s.peers.get(&ip);
+                    peers.push(PeerInfo {
+                        public_key: peer.public_key.clone(),
+                        address: Some(peer.ip),
+                        endpoint: session.map(|p| p.addr),
+                        last_handshake_sec: session.map(|p| p.handshake_at.elapsed().as_secs()),
+                        traffic: session.map(|p| p.sessions.traffic()).unwrap_or_default(),
+                        queue_drops: None
+                    });
+                }
+                Response::Peers(peers)
+            },
+            Request::AddPeer { peer } => self.edit_peers(|peers| {
+                if peers.iter().any(|p| p.ip == peer.ip || p.public_key == peer.public_key) {
+                    return Err(format!("There is a peer with address {} or this public key already", peer.ip));
+                }
+                peers.push(peer);
+                Ok(())
+            }).await,
+            Request::RemovePeer { ip } => self.edit_peers(|peers| {
+                let count = peers.len();
+                peers.retain(|p| p.ip != ip);
+                if peers.len() == count { Err(format!("There is no peer with address {}", ip)) } else { Ok(()) }
+            }).await,
+            Request::Kick { ip } => {
+                let plp = self.state.peers.lock().await;
+                let mut s = self.state.shards.lock_peer(&ip).await;
+                let Some(mut p) = s.peers.remove(&ip) else { return Response::Error(format!("There is no session for {}", ip)); };
+                info!("Peer {} ({}) disconnected: kicked", ip, p.addr);
+                p.account_configured(&plp, &ip, &mut *self.state.usage.lock().await);
+                s.prune_indices();
+                Response::Ok
+            }
+        }
+    }
+
+    /// Items dropped so far by the tun, socket and worker queues.
+    fn queue_drops(&self) -> [(&'static str, u64); 3] {
+        [("tun", self.tun_queue.drops()), ("socket", self.sock_queue.drops()), ("worker", self.jobs.iter().map(Queue::drops).sum())]
+    }
+
+    async fn metrics(&self) -> String {
+        let plp = self.state.peers.lock().await;
+        let configured = plp.len();
+        let usage = self.state.usage.lock().await;
+        let quotas = plp.iter()
+            .filter_map(|p| p.quota.as_ref().map(|q| (p.ip.to_string(), q.bytes, usage.used(&p.public_key, q))))
+            .collect::<Vec<_>>();
+        drop(usage);
+        drop(plp);
+        let mut peers = Vec::new();
+        for shard in self.state.shards.iter() {
+            peers.extend(shard.lock().await.peers.iter().map(|(ip, p)| (ip.to_string(), p.sessions.traffic(), p.spoofed, p.throttled)));
+        }
+        peers.sort_by(|a, b| a.0.cmp(&b.0));
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+        let mut m = Exposition::default();
+        m.single("frida_uptime_seconds", "gauge", "Seconds since the server started.", self.started.elapsed().as_secs());
+        m.single("frida_peers", "gauge", "Peers in the config.", configured);
+        m.single("frida_sessions", "gauge", "Connected peers.", peers.len());
+        type Field = fn(&(String, Traffic, u64, u64)) -> u64;
+        let per_peer: [(&str, &str, Field); 7] = [
+            ("frida_peer_rx_bytes_total", "Bytes received from the peer, keepalives included.", |p| p.1.rx_bytes),
+            ("frida_peer_rx_packets_total", "Packets received from the peer, keepalives included.", |p| p.1.rx_packets),
+            ("frida_peer_tx_bytes_total", "Bytes sent to the peer, keepalives included.", |p| p.1.tx_bytes),
+            ("frida_peer_tx_packets_total", "Packets sent to the peer, keepalives included.", |p| p.1.tx_packets),
+            ("frida_peer_decrypt_failures_total", "Packets from the peer that failed to decrypt.", |p| p.1.decrypt_failures),
+            ("frida_peer_spoofed_packets_total", "Packets from the peer dropped for a source outside its allowed ranges.", |p| p.2),
+            ("frida_peer_throttled_packets_total", "Packets to or from the peer dropped by its rate limit.", |p| p.3)
+        ];
+        for (name, help, field) in per_peer {
+            m.family(name, "counter", help);
+            peers.iter().for_each(|p| m.sample(name, &[("peer", &p.0)], field(p)));
+        }
+        m.family("frida_peer_quota_bytes", "gauge", "Bytes the peer may use in its quota period.");
+        quotas.iter().for_each(|q| m.sample("frida_peer_quota_bytes", &[("peer", &q.0)], q.1));
+        m.family("frida_peer_quota_used_bytes", "gauge", "Bytes the peer used in its quota period, as of the last second.");
+        quotas.iter().for_each(|q| m.sample("frida_peer_quota_used_bytes", &[("peer", &q.0)], q.2));
+        m.single("frida_handshakes_accepted_total", "counter", "Handshake initiations answered.", load(&self.counters.handshakes));
+        m.family("frida_handshakes_rejected_total", "counter", "Handshake initiations not answered, by reason.");
+        for (reason, count) in REJECT_REASONS.iter().zip(&self.counters.rejects) {
+            m.sample("frida_handshakes_rejected_total", &[("reason", reason)], load(count));
+        }
+        m.family("frida_decrypt_errors_total", "counter", "Data packets that failed to decrypt, by reason.");
+        for (reason, count) in DECRYPT_ERRORS.iter().zip(&self.counters.decrypt_errors) {
+            m.sample("frida_decrypt_errors_total", &[("reason", reason)], load(count));
+        }
+        m.single("frida_malformed_datagrams_total", "counter", "Datagrams that could not be decoded.", load(&self.counters.malformed));
+        m.family("frida_tun_errors_total", "counter", "Failed reads from and writes to the tun device.");
+        m.sample("frida_tun_errors_total", &[("op", "read")], load(&self.counters.tun_read_errors));
+        m.sample("frida_tun_errors_total", &[("op", "write")], load(&self.counters.tun_write_errors));
+        m.family("frida_queue_depth", "gauge", "Packets waiting in an internal queue.");
+        m.sample("frida_queue_depth", &[("queue", "tun")], self.tun_queue.depth() as u64);
+        m.sample("frida_queue_depth", &[("queue", "socket")], self.sock_queue.depth() as u64);
+        for (worker, jobs) in self.jobs.iter().enumerate() {
+            m.sample("frida_queue_depth", &[("queue", "worker"), ("worker", &worker.to_string())], jobs.depth() as u64);
+        }
+        m.family("frida_queue_drops_total", "counter", "Packets dropped because an internal queue was full.");
+        for (queue, drops) in self.queue_drops() {
+            m.sample("frida_queue_drops_total", &[("queue", queue)], drops);
+        }
+        m.finish()
+    }
+
+    /// Changes the peers in the config file, then applies them. The file
+    /// watcher sees the change too, but finds nothing left to do. A change
+    /// leaving invalid peers is not written.
+    async fn edit_peers(&self, change: impl FnOnce(&mut Vec<ServerPeer>) -> Result<(), String>) -> Response {
+        let result = read_config(&self.config_path).and_then(|mut config| {
+            change(&mut config.peers)?;
+            check_peers(&config.peers)?;
+            let raw = serde_yaml::to_string(&config).map_err(|e| e.to_string())?;
+            fs::write(&self.config_path, raw).map_err(|e| e.to_string())?;
+            Ok(config.peers)
+        });
+        let peers = match result {
+            Ok(peers) => peers,
+            Err(e) => return Response::Error(e)
+        };
+        match self.state.apply(peers, "control request").await {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(e)
+        }
+    }
+}
+
+/// Adds or deletes the kernel routes sending peers' allowed ranges into tun0.
+fn peer_routes<'a>(action: &str, ranges: impl Iterator<Item = &'a Cidr>) {
+    for range in ranges {
+        let ip_output = Command::new("ip")
+            .arg("route")
+            .arg(action)
+            .arg(range.to_string())
+            .arg("dev")
+            .arg("tun0")
+            .output()
+            .expect("Failed to execute ip route command.");
+
+        if !ip_output.status.success() {
+            error!("Failed to {} route {} to the tunnel: {:?}", action, range, String::from_utf8_lossy(&ip_output.stderr));
+        }
+    }
+}
+
+/// The tun device is always set up as a /24.
+fn subnet_broadcast(internal_address: &str) -> Ipv4Addr {
+    let [a, b, c, _] = internal_address.parse::<Ipv4Addr>().expect("Bad internal address").octets();
+    Ipv4Addr::new(a, b, c, 255)
+}
+
+/// Source and destination of an IPv4 or IPv6 packet.
+fn packet_addresses(packet: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let address = |at: usize| IpAddr::V4(Ipv4Addr::new(packet[at], packet[at + 1], packet[at + 2], packet[at + 3]));
+            Some((address(12), address(16)))
+        },
+        6 if packet.len() >= 40 => {
+            let address = |at: usize| IpAddr::V6(<[u8; 16]>::try_from(&packet[at..at + 16]).unwrap().into());
+            Some((address(8), address(24)))
+        },
+        _ => None
+    }
+}
+
+fn is_broadcast(packet: &[u8], subnet_broadcast: Ipv4Addr) -> bool {
+    matches!(packet_addresses(packet), Some((_, IpAddr::V4(d))) if d.is_broadcast() || d == subnet_broadcast)
+}
+
+/// Encrypts `packet` separately for every established peer but `sender`.
+fn broadcast(peers: &mut HashMap<IpAddr, UDPeer>, packet: &[u8], sender: IpAddr, rekey: &RekeyConfig, out: &mut SendBatch) {
+    for (_, peer) in peers.iter_mut().filter(|(ip, p)| **ip != sender && p.sessions.is_established()) {
+        if !peer.admit_egress(packet.len()) { continue; }
+        out.push_with(peer.addr, |buf| peer.sessions.encrypt(packet, buf, rekey));
+    }
 }
 
 struct UDPeer {
     addr: SocketAddr,
-    shared_secret: [u8; 32]
+    sessions: Sessions,
+    allowed_ips: Vec<Cidr>, // valid source addresses, the peer's own first
+    ingress: Option<TokenBucket>, // bytes the peer may send
+    egress: Option<TokenBucket>, // bytes it may be sent
+    spoofed: u64,
+    throttled: u64, // packets dropped by the rate limit
+    accounted: u64, // traffic bytes already added to the quota usage
+    over_quota: bool, // and throttled for it
+    connected_at: Instant,
+    handshake_at: Instant // last accepted initiation
+}
+
+impl UDPeer {
+    fn new(addr: SocketAddr, limit: &RateLimit) -> Self {
+        UDPeer {
+            addr,
+            sessions: Sessions::default(),
+            allowed_ips: Vec::new(),
+            ingress: limit.bucket(limit.ingress_bps),
+            egress: limit.bucket(limit.egress_bps),
+            spoofed: 0,
+            throttled: 0,
+            accounted: 0,
+            over_quota: false,
+            connected_at: Instant::now(),
+            handshake_at: Instant::now()
+        }
+    }
+
+    fn admit_ingress(&mut self, len: usize) -> bool {
+        let admitted = self.ingress.as_mut().is_none_or(|b| b.take(len as u64));
+        self.throttled += u64::from(!admitted);
+        admitted
+    }
+
+    fn admit_egress(&mut self, len: usize) -> bool {
+        let admitted = self.egress.as_mut().is_none_or(|b| b.take(len as u64));
+        self.throttled += u64::from(!admitted);
+        admitted
+    }
+
+    /// Adds the traffic since the last call to the quota of `peer`.
+    /// Returns whether the quota is used up.
+    fn account(&mut self, peer: &ServerPeer, usage: &mut Usage) -> bool {
+        let Some(quota) = &peer.quota else { return false; };
+        let traffic = self.sessions.traffic();
+        let total = traffic.rx_bytes + traffic.tx_bytes;
+        let used = usage.add(&peer.public_key, quota, total - self.accounted);
+        self.accounted = total;
+        used >= quota.bytes
+    }
+
+    /// Like `account`, for the peer configured with address `ip`.
+    fn account_configured(&mut self, configured: &[ServerPeer], ip: &IpAddr, usage: &mut Usage) {
+        if let Some(peer) = configured.iter().find(|c| IpAddr::V4(c.ip) == *ip) {
+            self.account(peer, usage);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,425 @@
+use std::{fmt, time::{Duration, Instant}};
+use aes_gcm::{ aead::{AeadInPlace, KeyInit},
+Aes256Gcm, Nonce, Tag };
+use chacha20poly1305::ChaCha20Poly1305;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{CipherSuite, RekeyConfig};
+use crate::noise::TransportKeys;
+use crate::udp::{UDPVpnPacket, PACKET_HEADER_LEN, TAG_LEN};
+
+// RFC 6479 sliding window: a ring of 64-bit blocks where one block is kept
+// free so that advancing the window only clears whole blocks.
+const BLOCK_BITS: u64 = 64;
+const RING_BLOCKS: usize = 32;
+const WINDOW_SIZE: u64 = (RING_BLOCKS as u64 - 1) * BLOCK_BITS;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecryptError {
+    NoSession,
+    Replay,
+    Auth
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptError::NoSession => write!(f, "no session"),
+            DecryptError::Replay => write!(f, "replayed or too old counter"),
+            DecryptError::Auth => write!(f, "authentication failed")
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// Anti-replay window over received packet counters.
+pub struct ReplayWindow {
+    last: u64,
+    bitmap: [u64; RING_BLOCKS]
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        ReplayWindow { last: 0, bitmap: [0; RING_BLOCKS] }
+    }
+}
+
+impl ReplayWindow {
+    fn position(counter: u64) -> (usize, u64) {
+        (((counter / BLOCK_BITS) % RING_BLOCKS as u64) as usize, 1 << (counter % BLOCK_BITS))
+    }
+
+    /// Returns true if `counter` has not been seen and is not too old.
+    /// Cheap enough to run before decryption.
+    pub fn check(&self, counter: u64) -> bool {
+        if counter > self.last {
+            return true;
+        }
+        if counter.saturating_add(WINDOW_SIZE) < self.last {
+            return false;
+        }
+        let (block, bit) = Self::position(counter);
+        self.bitmap[block] & bit == 0
+    }
+
+    /// Marks `counter` as seen. Must only be called for authenticated packets.
+    pub fn update(&mut self, counter: u64) {
+        if counter > self.last {
+            let current = self.last / BLOCK_BITS;
+            let diff = (counter / BLOCK_BITS - current).min(RING_BLOCKS as u64);
+            for i in 1..=diff {
+                self.bitmap[((current + i) % RING_BLOCKS as u64) as usize] = 0;
+            }
+            self.last = counter;
+        }
+        let (block, bit) = Self::position(counter);
+        self.bitmap[block] |= bit;
+    }
+}
+
+// Keepalives are ordinary data packets whose plaintext no IP packet can
+// have: an empty one asks the peer to answer with a single zero byte.
+const KEEPALIVE_REPLY: &[u8] = &[0];
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut n = [0u8; 12];
+    n[4..].copy_from_slice(&counter.to_le_bytes());
+    n
+}
+
+/// Keyed AEAD for one direction of a session, set up once at handshake time.
+enum Cipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305)
+}
+
+impl Cipher {
+    fn new(suite: CipherSuite, key: &[u8; 32]) -> Self {
+        match suite {
+            CipherSuite::Aes256Gcm => Cipher::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            CipherSuite::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into()))
+        }
+    }
+
+    fn seal(&self, counter: u64, buffer: &mut [u8]) -> Option<Tag> {
+        let nonce = nonce(counter);
+        let nonce = Nonce::from_slice(&nonce);
+        match self {
+            Cipher::Aes256Gcm(c) => c.encrypt_in_place_detached(nonce, &[], buffer),
+            Cipher::ChaCha20Poly1305(c) => c.encrypt_in_place_detached(nonce, &[], buffer)
+        }.ok()
+    }
+
+    /// Leaves `buffer` untouched if authentication fails.
+    fn open(&self, counter: u64, buffer: &mut [u8], tag: &[u8]) -> bool {
+        let nonce = nonce(counter);
+        let nonce = Nonce::from_slice(&nonce);
+        let tag = Tag::from_slice(tag);
+        match self {
+            Cipher::Aes256Gcm(c) => c.decrypt_in_place_detached(nonce, &[], buffer, tag),
+            Cipher::ChaCha20Poly1305(c) => c.decrypt_in_place_detached(nonce, &[], buffer, tag)
+        }.is_ok()
+    }
+}
+
+/// Cipher state of one completed handshake together with its usage. The
+/// transport keys are zeroized as soon as the ciphers are set up.
+/// Every key is used with a counter nonce, so a session must not send more
+/// than `u64::MAX` packets; the rekey limits keep it far below that.
+pub struct Session {
+    send: Cipher,
+    recv: Cipher,
+    local_index: u32,
+    remote_index: u32,
+    created: Instant,
+    bytes: u64,
+    packets: u64,
+    send_counter: u64,
+    replay: ReplayWindow
+}
+
+impl Session {
+    /// `local_index` is what the peer puts in packets for this session and
+    /// `remote_index` what this side puts in its own.
+    pub fn new(keys: TransportKeys, suite: CipherSuite, local_index: u32, remote_index: u32) -> Self {
+        Session { send: Cipher::new(suite, &keys.send), recv: Cipher::new(suite, &keys.recv), local_index, remote_index, created: Instant::now(), bytes: 0, packets: 0, send_counter: 0, replay: ReplayWindow::default() }
+    }
+
+    fn count(&mut self, len: usize) {
+        self.bytes += len as u64;
+        self.packets += 1;
+    }
+
+    /// Replaces the contents of `out` with `plain` as a serialized
+    /// `UDPVpnPacket`, sealed in place. A reused `out` saves allocating.
+    pub fn encrypt(&mut self, plain: &[u8], out: &mut Vec<u8>) -> bool {
+        let counter = self.send_counter;
+        let Some(next) = counter.checked_add(1) else { return false; };
+        self.send_counter = next;
+        out.clear();
+        out.extend_from_slice(&UDPVpnPacket::header(self.remote_index, counter));
+        out.extend_from_slice(plain);
+        let Some(tag) = self.send.seal(counter, &mut out[PACKET_HEADER_LEN..]) else { return false; };
+        out.extend_from_slice(&tag);
+        self.count(plain.len());
+        true
+    }
+
+    /// Decrypts `packet.data` in place, leaving the plaintext without the tag.
+    pub fn decrypt(&mut self, packet: &mut UDPVpnPacket) -> Result<(), DecryptError> {
+        if !self.replay.check(packet.counter) {
+            return Err(DecryptError::Replay);
+        }
+        let plain_len = packet.data.len() - TAG_LEN;
+        let (cipher, tag) = packet.data.split_at_mut(plain_len);
+        if !self.recv.open(packet.counter, cipher, tag) {
+            return Err(DecryptError::Auth);
+        }
+        packet.data = &mut std::mem::take(&mut packet.data)[..plain_len];
+        self.replay.update(packet.counter);
+        self.count(plain_len);
+        Ok(())
+    }
+
+    pub fn rekey_due(&self, cfg: &RekeyConfig) -> bool {
+        self.created.elapsed() >= Duration::from_secs(cfg.interval_sec)
+            || self.bytes >= cfg.max_bytes
+            || self.packets >= cfg.max_packets
+    }
+
+    pub fn expired(&self, cfg: &RekeyConfig) -> bool {
+        self.created.elapsed() >= Duration::from_secs(cfg.interval_sec.saturating_mul(2))
+            || self.bytes >= cfg.max_bytes.saturating_mul(2)
+            || self.packets >= cfg.max_packets.saturating_mul(2)
+    }
+}
+
+/// Traffic with one peer in datagrams and their bytes on the wire,
+/// keepalives included.
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug, Clone, Copy)]
+pub struct Traffic {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub decrypt_failures: u64
+}
+
+/// All sessions with one peer. `next` holds a responder session until the
+/// initiator proves it has the keys by sending the first packet with them,
+/// `previous` keeps decrypting in-flight packets for the overlap window.
+/// Dropped sessions have their keys zeroized. Liveness is tracked across
+/// rotations from authenticated packets only.
+#[derive(Default)]
+pub struct Sessions {
+    current: Option<Session>,
+    previous: Option<(Session, Instant)>,
+    next: Option<Session>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+    unanswered_since: Option<Instant>,
+    last_probe: Option<Instant>,
+    reply_due: bool,
+    rtt: Option<Duration>,
+    traffic: Traffic
+}
+
+impl Sessions {
+    pub fn current(&self) -> Option<&Session> {
+        self.current.as_ref()
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn last_sent(&self) -> Option<Instant> {
+        self.last_sent
+    }
+
+    pub fn last_received(&self) -> Option<Instant> {
+        self.last_received
+    }
+
+    pub fn last_probe(&self) -> Option<Instant> {
+        self.last_probe
+    }
+
+    /// Round trip time of the last answered keepalive.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Counted across rotations, for as long as the peer is known.
+    pub fn traffic(&self) -> Traffic {
+        self.traffic
+    }
+
+    /// False once a keepalive has gone unanswered for `timeout`.
+    pub fn is_responsive(&self, timeout: Duration) -> bool {
+        self.unanswered_since.is_none_or(|sent| sent.elapsed() < timeout)
+    }
+
+    /// Makes `session` current, retiring the old one into the overlap window.
+    pub fn rotate(&mut self, session: Session) {
+        self.previous = self.current.replace(session).map(|s| (s, Instant::now()));
+    }
+
+    pub fn set_next(&mut self, session: Session) {
+        self.next = Some(session);
+    }
+
+    /// Whether one of the sessions was assigned `index` locally.
+    pub fn has_index(&self, index: u32) -> bool {
+        self.current.as_ref().is_some_and(|s| s.local_index == index)
+            || self.next.as_ref().is_some_and(|s| s.local_index == index)
+            || self.previous.as_ref().is_some_and(|(s, _)| s.local_index == index)
+    }
+
+    /// Drops the previous session once its overlap window is over and the
+    /// current one once it is past its hard limits.
+    pub fn expire(&mut self, cfg: &RekeyConfig) {
+        if self.previous.as_ref().is_some_and(|(_, retired)| retired.elapsed() >= Duration::from_secs(cfg.overlap_sec)) {
+            self.previous = None;
+        }
+        if self.current.as_ref().is_some_and(|s| s.expired(cfg)) {
+            self.current = None;
+        }
+        if self.next.as_ref().is_some_and(|s| s.expired(cfg)) {
+            self.next = None;
+        }
+    }
+
+    /// Writes the packet for `plain` into `out`, see `Session::encrypt`.
+    /// Returns false if there is no session to send with.
+    pub fn encrypt(&mut self, plain: &[u8], out: &mut Vec<u8>, cfg: &RekeyConfig) -> bool {
+        self.expire(cfg);
+        if !self.current.as_mut().is_some_and(|s| s.encrypt(plain, out)) {
+            return false;
+        }
+        self.last_sent = Some(Instant::now());
+        self.traffic.tx_bytes += out.len() as u64;
+        self.traffic.tx_packets += 1;
+        true
+    }
+
+    /// Encrypted keepalive that the peer answers, timing the round trip.
+    pub fn keepalive(&mut self, cfg: &RekeyConfig) -> Option<Vec<u8>> {
+        let mut packet = Vec::new();
+        if !self.encrypt(&[], &mut packet, cfg) {
+            return None;
+        }
+        let now = Instant::now();
+        self.unanswered_since.get_or_insert(now);
+        self.last_probe = Some(now);
+        Some(packet)
+    }
+
+    /// Writes the answer to a keepalive received since the last call into
+    /// `out`, if one is due.
+    pub fn keepalive_reply(&mut self, out: &mut Vec<u8>, cfg: &RekeyConfig) -> bool {
+        std::mem::take(&mut self.reply_due) && self.encrypt(KEEPALIVE_REPLY, out, cfg)
+    }
+
+    /// Decrypts in place and returns the IP packet, or an empty one for
+    /// keepalives.
+    pub fn decrypt<'p>(&mut self, packet: &'p mut UDPVpnPacket, cfg: &RekeyConfig) -> Result<&'p [u8], DecryptError> {
+        let wire_len = (PACKET_HEADER_LEN + packet.data.len()) as u64;
+        if let Err(e) = self.open(packet, cfg) {
+            self.traffic.decrypt_failures += 1;
+            return Err(e);
+        }
+        self.traffic.rx_bytes += wire_len;
+        self.traffic.rx_packets += 1;
+        let now = Instant::now();
+        self.last_received = Some(now);
+        match &packet.data[..] {
+            [] => self.reply_due = true,
+            KEEPALIVE_REPLY => if self.unanswered_since.take().is_some() {
+                self.rtt = self.last_probe.map(|sent| now - sent);
+            },
+            _ => return Ok(packet.data)
+        }
+        Ok(&[])
+    }
+
+    /// Decrypts with the session the packet's receiver index names. The
+    /// pending session becomes current on its first packet.
+    fn open(&mut self, packet: &mut UDPVpnPacket, cfg: &RekeyConfig) -> Result<(), DecryptError> {
+        self.expire(cfg);
+        let index = packet.receiver;
+        if let Some(session) = self.current.as_mut().filter(|s| s.local_index == index) {
+            return session.decrypt(packet);
+        }
+        if let Some(session) = self.next.as_mut().filter(|s| s.local_index == index) {
+            session.decrypt(packet)?;
+            let confirmed = self.next.take().unwrap();
+            self.rotate(confirmed);
+            return Ok(());
+        }
+        if let Some((session, _)) = self.previous.as_mut().filter(|(s, _)| s.local_index == index) {
+            return session.decrypt(packet);
+        }
+        Err(DecryptError::NoSession)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(window: &mut ReplayWindow, counter: u64) -> bool {
+        let fresh = window.check(counter);
+        if fresh {
+            window.update(counter);
+        }
+        fresh
+    }
+
+    #[test]
+    fn duplicate_counters() {
+        let mut window = ReplayWindow::default();
+        assert!(accept(&mut window, 0));
+        assert!(!accept(&mut window, 0));
+        assert!(accept(&mut window, 5));
+        assert!(!accept(&mut window, 5));
+        assert!(accept(&mut window, 3));
+        assert!(!accept(&mut window, 3));
+    }
+
+    #[test]
+    fn window_edge() {
+        let last = 10_000;
+        let mut window = ReplayWindow::default();
+        assert!(accept(&mut window, last));
+        assert!(!window.check(last - WINDOW_SIZE - 1));
+        assert!(accept(&mut window, last - WINDOW_SIZE));
+        assert!(!accept(&mut window, last - WINDOW_SIZE));
+        assert!(accept(&mut window, last + 1));
+        assert!(!window.check(last - WINDOW_SIZE));
+    }
+
+    #[test]
+    fn large_jump() {
+        let mut window = ReplayWindow::default();
+        (0..200).for_each(|counter| assert!(accept(&mut window, counter)));
+        let far = 199 + RING_BLOCKS as u64 * BLOCK_BITS * 3 + 17;
+        assert!(accept(&mut window, far));
+        assert!(!accept(&mut window, far));
+        assert!(!window.check(199));
+        // Every block was cleared, so counters within the new window that
+        // share a bit position with old ones are still fresh.
+        (far - WINDOW_SIZE..far).for_each(|counter| assert!(window.check(counter), "{}", counter));
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut window = ReplayWindow::default();
+        let counters = [100, 90, 101, 50, 99, 1000, 980, 999];
+        counters.iter().for_each(|&counter| assert!(accept(&mut window, counter), "{}", counter));
+        counters.iter().for_each(|&counter| assert!(!window.check(counter), "{}", counter));
+        assert!(accept(&mut window, 998));
+        assert!(accept(&mut window, 60));
+    }
+}
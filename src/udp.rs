@@ -1,54 +1,345 @@
+use std::{fmt, net::Ipv4Addr};
 
-use std::net::Ipv4Addr;
-use chrono::{Timelike, Utc};
+pub const HANDSHAKE_HEADER: u8 = 0;
+pub const PACKET_HEADER: u8 = 1; // also carries keepalives, see session.rs
+pub const HANDSHAKE_RESPONSE_HEADER: u8 = 3;
+pub const COOKIE_REPLY_HEADER: u8 = 4;
 
-pub struct UDPVpnPacket {
-    pub nonce: Vec<u8>, // [u8; 12]
-    pub data: Vec<u8>
+const INDEX_LEN: usize = 4;
+const COUNTER_LEN: usize = 8;
+pub const TAG_LEN: usize = 16;
+const PUBLIC_KEY_LEN: usize = 32;
+const TIMESTAMP_LEN: usize = 12;
+pub const MAC_LEN: usize = 16;
+const COOKIE_NONCE_LEN: usize = 24;
+
+const HANDSHAKE_MIN_LEN: usize = 1 + INDEX_LEN + PUBLIC_KEY_LEN + (PUBLIC_KEY_LEN + TAG_LEN) + TAG_LEN + 2 * MAC_LEN;
+const HANDSHAKE_RESPONSE_MIN_LEN: usize = 1 + 2 * INDEX_LEN + PUBLIC_KEY_LEN + TAG_LEN;
+const HANDSHAKE_PAYLOAD_MIN_LEN: usize = TIMESTAMP_LEN + 4 + 1;
+pub const PACKET_HEADER_LEN: usize = 1 + INDEX_LEN + COUNTER_LEN;
+const PACKET_MIN_LEN: usize = PACKET_HEADER_LEN + TAG_LEN;
+const COOKIE_REPLY_LEN: usize = 1 + COOKIE_NONCE_LEN + MAC_LEN + TAG_LEN;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UDPError {
+    Empty,
+    UnknownHeader(u8),
+    BadHeader { expected: u8, actual: u8 },
+    TooShort { header: u8, expected: usize, actual: usize }
+}
+
+impl fmt::Display for UDPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UDPError::Empty => write!(f, "empty datagram"),
+            UDPError::UnknownHeader(h) => write!(f, "unknown header value {}", h),
+            UDPError::BadHeader { expected, actual } => write!(f, "expected header {}, got {}", expected, actual),
+            UDPError::TooShort { header, expected, actual } => write!(f, "message {} is {} bytes long, at least {} expected", header, actual, expected)
+        }
+    }
 }
 
-pub struct UDPKeepAlive {
+impl std::error::Error for UDPError {}
 
+fn check(data: &[u8], header: u8, min_len: usize) -> Result<(), UDPError> {
+    match data.first() {
+        None => Err(UDPError::Empty),
+        Some(&h) if h != header => Err(UDPError::BadHeader { expected: header, actual: h }),
+        Some(_) if data.len() < min_len => Err(UDPError::TooShort { header, expected: min_len, actual: data.len() }),
+        Some(_) => Ok(())
+    }
 }
 
-impl UDPSerializable for UDPKeepAlive {
-    fn serialize(&self) -> Vec<u8> {
-        let h: &[u8] = &[2];
-        [h, &[Utc::now().second() as u8]].concat()
+fn read_index(data: &[u8], at: usize) -> u32 {
+    let mut index = [0u8; INDEX_LEN];
+    index.copy_from_slice(&data[at..at + INDEX_LEN]);
+    u32::from_le_bytes(index)
+}
+
+pub enum Message<'a> {
+    Handshake(UDPVpnHandshake),
+    HandshakeResponse(UDPVpnHandshakeResponse),
+    CookieReply(UDPCookieReply),
+    Packet(UDPVpnPacket<'a>)
+}
+
+/// Parses a single datagram received from the socket. A data packet keeps
+/// borrowing `data`, to be decrypted where it was received.
+pub fn decode(data: &mut [u8]) -> Result<Message<'_>, UDPError> {
+    match data.first() {
+        Some(&HANDSHAKE_HEADER) => UDPVpnHandshake::deserialize(data).map(Message::Handshake),
+        Some(&HANDSHAKE_RESPONSE_HEADER) => UDPVpnHandshakeResponse::deserialize(data).map(Message::HandshakeResponse),
+        Some(&COOKIE_REPLY_HEADER) => UDPCookieReply::deserialize(data).map(Message::CookieReply),
+        Some(&PACKET_HEADER) => UDPVpnPacket::deserialize(data).map(Message::Packet),
+        Some(&h) => Err(UDPError::UnknownHeader(h)),
+        None => Err(UDPError::Empty)
     }
 }
 
-impl UDPSerializable for UDPVpnPacket {
+pub struct UDPVpnPacket<'a> {
+    pub receiver: u32, // index the receiving side assigned to the session
+    pub counter: u64, // nonce of the packet within its session
+    pub data: &'a mut [u8] // ciphertext and tag, the plaintext once decrypted
+}
+
+impl UDPSerializable for UDPVpnPacket<'_> {
     fn serialize(&self) -> Vec<u8> {
-        let h: &[u8] = &[1];
-        [h, &self.nonce, &self.data[..]].concat()
+        let h: &[u8] = &[PACKET_HEADER];
+        [h, &self.receiver.to_le_bytes(), &self.counter.to_le_bytes(), &self.data[..]].concat()
     }
 }
 
-impl UDPVpnPacket {
-    pub fn deserialize(data: &Vec<u8>) -> Self {
-        UDPVpnPacket { nonce: data[1..=12].to_vec(), data: data[13..].to_vec() }
+impl<'a> UDPVpnPacket<'a> {
+    /// Header preceding the ciphertext, for callers that seal in place.
+    pub fn header(receiver: u32, counter: u64) -> [u8; PACKET_HEADER_LEN] {
+        let mut h = [PACKET_HEADER; PACKET_HEADER_LEN];
+        h[1..=INDEX_LEN].copy_from_slice(&receiver.to_le_bytes());
+        h[1 + INDEX_LEN..].copy_from_slice(&counter.to_le_bytes());
+        h
+    }
+
+    pub fn deserialize(data: &'a mut [u8]) -> Result<Self, UDPError> {
+        check(data, PACKET_HEADER, PACKET_MIN_LEN)?;
+        let receiver = read_index(data, 1);
+        let mut counter = [0u8; COUNTER_LEN];
+        counter.copy_from_slice(&data[1 + INDEX_LEN..PACKET_HEADER_LEN]);
+        Ok(UDPVpnPacket { receiver, counter: u64::from_le_bytes(counter), data: &mut data[PACKET_HEADER_LEN..] })
     }
 }
 
+/// First Noise IK message (`-> e, es, s, ss`), sent by the client.
+/// `mac1` and `mac2` cover every byte before them, see `cookie.rs`.
 pub struct UDPVpnHandshake {
-    pub public_key: Vec<u8>,
-    pub request_ip: Ipv4Addr // [u8; 4]
+    pub sender: u32, // index the client assigned to the new session
+    pub ephemeral: [u8; 32],
+    pub encrypted_static: Vec<u8>, // [u8; 48]
+    pub encrypted_payload: Vec<u8>,
+    pub mac1: [u8; MAC_LEN],
+    pub mac2: [u8; MAC_LEN]
 }
 
 impl UDPSerializable for UDPVpnHandshake {
     fn serialize(&self) -> Vec<u8> {
-        let h: &[u8] = &[0];
-        [h, &self.public_key[..], &self.request_ip.octets()].concat()
+        let h: &[u8] = &[HANDSHAKE_HEADER];
+        [h, &self.sender.to_le_bytes(), &self.ephemeral, &self.encrypted_static[..], &self.encrypted_payload[..], &self.mac1, &self.mac2].concat()
     }
 }
 
 impl UDPVpnHandshake {
-    pub fn deserialize(data: &Vec<u8>) -> Self {
-        UDPVpnHandshake { public_key: data[1..=32].to_vec(), request_ip: Ipv4Addr::new(data[33], data[34], data[35], data[36]) }
+    pub fn deserialize(data: &[u8]) -> Result<Self, UDPError> {
+        check(data, HANDSHAKE_HEADER, HANDSHAKE_MIN_LEN)?;
+        let ephemeral_start = 1 + INDEX_LEN;
+        let mut ephemeral = [0u8; 32];
+        ephemeral.copy_from_slice(&data[ephemeral_start..ephemeral_start + PUBLIC_KEY_LEN]);
+        let static_end = ephemeral_start + PUBLIC_KEY_LEN + PUBLIC_KEY_LEN + TAG_LEN;
+        let mac1_start = data.len() - 2 * MAC_LEN;
+        let mut mac1 = [0u8; MAC_LEN];
+        let mut mac2 = [0u8; MAC_LEN];
+        mac1.copy_from_slice(&data[mac1_start..mac1_start + MAC_LEN]);
+        mac2.copy_from_slice(&data[mac1_start + MAC_LEN..]);
+        Ok(UDPVpnHandshake { 
+            sender: read_index(data, 1),
+            ephemeral, 
+            encrypted_static: data[ephemeral_start + PUBLIC_KEY_LEN..static_end].to_vec(), 
+            encrypted_payload: data[static_end..mac1_start].to_vec(),
+            mac1,
+            mac2
+        })
+    }
+}
+
+/// Second Noise IK message (`<- e, ee, se`), sent by the server.
+pub struct UDPVpnHandshakeResponse {
+    pub sender: u32, // index the server assigned to the new session
+    pub receiver: u32, // `sender` of the initiation being answered
+    pub ephemeral: [u8; 32],
+    pub encrypted_payload: Vec<u8>
+}
+
+impl UDPSerializable for UDPVpnHandshakeResponse {
+    fn serialize(&self) -> Vec<u8> {
+        let h: &[u8] = &[HANDSHAKE_RESPONSE_HEADER];
+        [h, &self.sender.to_le_bytes(), &self.receiver.to_le_bytes(), &self.ephemeral, &self.encrypted_payload[..]].concat()
+    }
+}
+
+impl UDPVpnHandshakeResponse {
+    pub fn deserialize(data: &[u8]) -> Result<Self, UDPError> {
+        check(data, HANDSHAKE_RESPONSE_HEADER, HANDSHAKE_RESPONSE_MIN_LEN)?;
+        let ephemeral_start = 1 + 2 * INDEX_LEN;
+        let mut ephemeral = [0u8; 32];
+        ephemeral.copy_from_slice(&data[ephemeral_start..ephemeral_start + PUBLIC_KEY_LEN]);
+        Ok(UDPVpnHandshakeResponse {
+            sender: read_index(data, 1),
+            receiver: read_index(data, 1 + INDEX_LEN),
+            ephemeral,
+            encrypted_payload: data[ephemeral_start + PUBLIC_KEY_LEN..].to_vec()
+        })
+    }
+}
+
+/// Sent by a server under load instead of a handshake response. Carries an
+/// encrypted cookie the client must use for `mac2` in its next initiation.
+pub struct UDPCookieReply {
+    pub nonce: [u8; COOKIE_NONCE_LEN],
+    pub encrypted_cookie: Vec<u8> // [u8; 32]
+}
+
+impl UDPSerializable for UDPCookieReply {
+    fn serialize(&self) -> Vec<u8> {
+        let h: &[u8] = &[COOKIE_REPLY_HEADER];
+        [h, &self.nonce, &self.encrypted_cookie[..]].concat()
+    }
+}
+
+impl UDPCookieReply {
+    pub fn deserialize(data: &[u8]) -> Result<Self, UDPError> {
+        check(data, COOKIE_REPLY_HEADER, COOKIE_REPLY_LEN)?;
+        let mut nonce = [0u8; COOKIE_NONCE_LEN];
+        nonce.copy_from_slice(&data[1..=COOKIE_NONCE_LEN]);
+        Ok(UDPCookieReply { nonce, encrypted_cookie: data[1 + COOKIE_NONCE_LEN..COOKIE_REPLY_LEN].to_vec() })
+    }
+}
+
+/// Plaintext carried inside the encrypted payload of `UDPVpnHandshake`.
+pub struct UDPHandshakePayload {
+    pub timestamp: [u8; 12],
+    pub request_ip: Ipv4Addr, // [u8; 4]
+    pub cipher_suites: Vec<u8> // ids of the suites the client supports
+}
+
+impl UDPSerializable for UDPHandshakePayload {
+    fn serialize(&self) -> Vec<u8> {
+        [&self.timestamp[..], &self.request_ip.octets(), &[self.cipher_suites.len() as u8], &self.cipher_suites[..]].concat()
+    }
+}
+
+impl UDPHandshakePayload {
+    pub fn deserialize(data: &[u8]) -> Result<Self, UDPError> {
+        let too_short = |expected| UDPError::TooShort { header: HANDSHAKE_HEADER, expected, actual: data.len() };
+        if data.len() < HANDSHAKE_PAYLOAD_MIN_LEN {
+            return Err(too_short(HANDSHAKE_PAYLOAD_MIN_LEN));
+        }
+        let mut timestamp = [0u8; 12];
+        timestamp.copy_from_slice(&data[..TIMESTAMP_LEN]);
+        let ip = &data[TIMESTAMP_LEN..TIMESTAMP_LEN + 4];
+        let suites_len = data[TIMESTAMP_LEN + 4] as usize;
+        let suites = data.get(HANDSHAKE_PAYLOAD_MIN_LEN..HANDSHAKE_PAYLOAD_MIN_LEN + suites_len)
+            .ok_or_else(|| too_short(HANDSHAKE_PAYLOAD_MIN_LEN + suites_len))?;
+        Ok(UDPHandshakePayload { timestamp, request_ip: Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]), cipher_suites: suites.to_vec() })
+    }
+}
+
+/// Plaintext carried inside the encrypted payload of `UDPVpnHandshakeResponse`.
+pub struct UDPHandshakeResponsePayload {
+    pub cipher_suite: u8
+}
+
+impl UDPSerializable for UDPHandshakeResponsePayload {
+    fn serialize(&self) -> Vec<u8> {
+        vec![self.cipher_suite]
+    }
+}
+
+impl UDPHandshakeResponsePayload {
+    pub fn deserialize(data: &[u8]) -> Result<Self, UDPError> {
+        match data.first() {
+            Some(&cipher_suite) => Ok(UDPHandshakeResponsePayload { cipher_suite }),
+            None => Err(UDPError::TooShort { header: HANDSHAKE_RESPONSE_HEADER, expected: 1, actual: 0 })
+        }
     }
 }
 
 pub trait UDPSerializable {
     fn serialize(&self) -> Vec<u8>;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake() -> UDPVpnHandshake {
+        UDPVpnHandshake {
+            sender: 7,
+            ephemeral: [1; 32],
+            encrypted_static: vec![2; PUBLIC_KEY_LEN + TAG_LEN],
+            encrypted_payload: vec![3; HANDSHAKE_PAYLOAD_MIN_LEN + 2 + TAG_LEN],
+            mac1: [4; MAC_LEN],
+            mac2: [5; MAC_LEN]
+        }
+    }
+
+    fn response() -> UDPVpnHandshakeResponse {
+        UDPVpnHandshakeResponse { sender: 8, receiver: 7, ephemeral: [6; 32], encrypted_payload: vec![9; 1 + TAG_LEN] }
+    }
+
+    fn cookie_reply() -> UDPCookieReply {
+        UDPCookieReply { nonce: [10; COOKIE_NONCE_LEN], encrypted_cookie: vec![11; MAC_LEN + TAG_LEN] }
+    }
+
+    fn packet() -> Vec<u8> {
+        UDPVpnPacket { receiver: 9, counter: u64::MAX - 1, data: &mut [12; 100] }.serialize()
+    }
+
+    #[test]
+    fn empty_and_unknown_headers() {
+        assert_eq!(decode(&mut []).err(), Some(UDPError::Empty));
+        assert_eq!(decode(&mut [2, 0, 0]).err(), Some(UDPError::UnknownHeader(2)));
+        assert_eq!(decode(&mut [255]).err(), Some(UDPError::UnknownHeader(255)));
+        assert_eq!(UDPVpnPacket::deserialize(&mut []).err(), Some(UDPError::Empty));
+        assert_eq!(UDPVpnPacket::deserialize(&mut [HANDSHAKE_HEADER; PACKET_MIN_LEN]).err(), Some(UDPError::BadHeader { expected: PACKET_HEADER, actual: HANDSHAKE_HEADER }));
+    }
+
+    #[test]
+    fn truncated_messages() {
+        let shortest = [
+            (handshake().serialize(), HANDSHAKE_HEADER, HANDSHAKE_MIN_LEN),
+            (response().serialize(), HANDSHAKE_RESPONSE_HEADER, HANDSHAKE_RESPONSE_MIN_LEN),
+            (cookie_reply().serialize(), COOKIE_REPLY_HEADER, COOKIE_REPLY_LEN),
+            (packet(), PACKET_HEADER, PACKET_MIN_LEN)
+        ];
+        for (mut bytes, header, min_len) in shortest {
+            assert!(decode(&mut bytes[..min_len]).is_ok(), "message {} at its minimum length", header);
+            for len in 1..min_len {
+                assert_eq!(decode(&mut bytes[..len]).err(), Some(UDPError::TooShort { header, expected: min_len, actual: len }));
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_payloads() {
+        let payload = UDPHandshakePayload { timestamp: [1; 12], request_ip: Ipv4Addr::new(10, 66, 66, 2), cipher_suites: vec![1, 2] }.serialize();
+        let full = HANDSHAKE_PAYLOAD_MIN_LEN + 2;
+        assert_eq!(UDPHandshakePayload::deserialize(&payload[..HANDSHAKE_PAYLOAD_MIN_LEN - 1]).err(), Some(UDPError::TooShort { header: HANDSHAKE_HEADER, expected: HANDSHAKE_PAYLOAD_MIN_LEN, actual: HANDSHAKE_PAYLOAD_MIN_LEN - 1 }));
+        assert_eq!(UDPHandshakePayload::deserialize(&payload[..full - 1]).err(), Some(UDPError::TooShort { header: HANDSHAKE_HEADER, expected: full, actual: full - 1 }));
+        assert_eq!(UDPHandshakeResponsePayload::deserialize(&[]).err(), Some(UDPError::TooShort { header: HANDSHAKE_RESPONSE_HEADER, expected: 1, actual: 0 }));
+    }
+
+    #[test]
+    fn round_trips() {
+        let Ok(Message::Handshake(h)) = decode(&mut handshake().serialize()) else { panic!("not a handshake") };
+        let expected = handshake();
+        assert_eq!((h.sender, h.ephemeral, h.mac1, h.mac2), (expected.sender, expected.ephemeral, expected.mac1, expected.mac2));
+        assert_eq!((h.encrypted_static, h.encrypted_payload), (expected.encrypted_static, expected.encrypted_payload));
+
+        let Ok(Message::HandshakeResponse(r)) = decode(&mut response().serialize()) else { panic!("not a handshake response") };
+        let expected = response();
+        assert_eq!((r.sender, r.receiver, r.ephemeral, r.encrypted_payload), (expected.sender, expected.receiver, expected.ephemeral, expected.encrypted_payload));
+
+        let Ok(Message::CookieReply(c)) = decode(&mut cookie_reply().serialize()) else { panic!("not a cookie reply") };
+        let expected = cookie_reply();
+        assert_eq!((c.nonce, c.encrypted_cookie), (expected.nonce, expected.encrypted_cookie));
+
+        let mut bytes = packet();
+        let Ok(Message::Packet(p)) = decode(&mut bytes) else { panic!("not a packet") };
+        assert_eq!((p.receiver, p.counter, &p.data[..]), (9, u64::MAX - 1, &[12; 100][..]));
+        let mut sealed = UDPVpnPacket::header(9, u64::MAX - 1).to_vec();
+        sealed.extend_from_slice(&[12; 100]);
+        assert_eq!(sealed, packet());
+
+        let payload = UDPHandshakePayload { timestamp: [1; 12], request_ip: Ipv4Addr::new(10, 66, 66, 2), cipher_suites: vec![1, 2] };
+        let decoded = UDPHandshakePayload::deserialize(&payload.serialize()).unwrap();
+        assert_eq!((decoded.timestamp, decoded.request_ip, decoded.cipher_suites), (payload.timestamp, payload.request_ip, payload.cipher_suites));
+        assert_eq!(UDPHandshakeResponsePayload::deserialize(&UDPHandshakeResponsePayload { cipher_suite: 2 }.serialize()).unwrap().cipher_suite, 2);
+    }
+}